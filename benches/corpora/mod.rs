@@ -0,0 +1,70 @@
+//! Synthetic corpora shared by the benchmarks and the compression report.
+
+use serde_json::Value;
+
+pub const MESSAGES: usize = 100;
+
+/// API-like responses: lists of users sharing most keys and many values.
+pub fn api_responses() -> Vec<Value> {
+    let first_names = ["Anna", "Tim", "Francis", "Agent"];
+    let last_names = ["Smith", "Doe", "Peterson"];
+    (0..MESSAGES)
+        .map(|i| {
+            let users: Vec<Value> = (0..20)
+                .map(|j| {
+                    json!({
+                        "id": i * 20 + j,
+                        "firstName": first_names[j % 4],
+                        "lastName": last_names[j % 3],
+                        "active": j % 2 == 0,
+                        "score": (i * j) as f64 / 10.0,
+                        "url": format!("https://api.example.com/users/{}", i * 20 + j),
+                        "tags": ["user", if j % 5 == 0 { "admin" } else { "member" }]
+                    })
+                })
+                .collect();
+            json!({"page": i, "users": users})
+        })
+        .collect()
+}
+
+/// Multi-line log batches with repeated prefixes and a few varying fields.
+pub fn logs() -> Vec<String> {
+    let levels = ["INFO", "INFO", "WARN", "DEBUG", "ERROR"];
+    let paths = ["/users", "/users/42", "/orders", "/health"];
+    (0..MESSAGES)
+        .map(|i| {
+            (0..20)
+                .map(|j| {
+                    format!(
+                        "2024-05-01T12:{:02}:{:02}Z {} http GET {} status={} duration_ms={}\n",
+                        i % 60,
+                        j,
+                        levels[(i + j) % levels.len()],
+                        paths[j % paths.len()],
+                        if j % 7 == 0 { 500 } else { 200 },
+                        (i * 7 + j * 3) % 50
+                    )
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Telemetry samples: fixed device metadata with monotonic timestamps and counters.
+pub fn telemetry() -> Vec<Value> {
+    (0..MESSAGES)
+        .map(|i| {
+            let start = 1_700_000_000_000u64 + i as u64 * 60_000;
+            let timestamps: Vec<u64> = (0..60).map(|j| start + j * 1000).collect();
+            let counters: Vec<u64> = (0..60).map(|j| (i as u64 * 60 + j) * 3).collect();
+            let temperatures: Vec<f64> = (0..60).map(|j| 20.0 + (j % 5) as f64 * 0.5).collect();
+            json!({
+                "device": {"id": "sensor-17", "site": "berlin", "firmware": "1.4.2"},
+                "timestamps": timestamps,
+                "requests": counters,
+                "temperature": temperatures
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,155 @@
+#[macro_use]
+extern crate criterion;
+#[macro_use]
+extern crate serde_json;
+extern crate jsonm;
+
+mod corpora;
+
+use criterion::{Criterion, Throughput};
+use jsonm::packer::{PackOptions, Packer};
+use jsonm::unpacker::Unpacker;
+use serde_json::Value;
+
+fn numeric_sequence_options() -> PackOptions {
+    let mut options = PackOptions::new();
+    options.pack_numeric_sequences = true;
+    options
+}
+
+fn bench_pack(c: &mut Criterion) {
+    let corpora = vec![
+        (
+            "api_responses",
+            corpora::api_responses(),
+            PackOptions::new(),
+        ),
+        ("telemetry", corpora::telemetry(), PackOptions::new()),
+        (
+            "telemetry_numeric_sequences",
+            corpora::telemetry(),
+            numeric_sequence_options(),
+        ),
+    ];
+
+    let mut group = c.benchmark_group("pack");
+    group.throughput(Throughput::Elements(corpora::MESSAGES as u64));
+    for (name, messages, options) in &corpora {
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                let mut packer = Packer::new();
+                for message in messages {
+                    packer.pack(message, options).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_pack_string(c: &mut Criterion) {
+    let logs = corpora::logs();
+    let options = PackOptions::new();
+
+    let mut group = c.benchmark_group("pack_string");
+    group.throughput(Throughput::Elements(corpora::MESSAGES as u64));
+    group.bench_function("logs", |b| {
+        b.iter(|| {
+            let mut packer = Packer::new();
+            for log in &logs {
+                packer.pack_string(log, &options).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+/// One large export made of all API responses, packed sequentially and, with the
+/// `rayon` feature, in parallel.
+fn bench_pack_export(c: &mut Criterion) {
+    let export = Value::Array(corpora::api_responses());
+    #[allow(unused_mut)]
+    let mut variants = vec![("sequential", PackOptions::new())];
+    #[cfg(feature = "rayon")]
+    variants.push((
+        "parallel",
+        PackOptions {
+            parallel_min_array_len: Some(16),
+            ..PackOptions::new()
+        },
+    ));
+
+    let mut group = c.benchmark_group("pack_export");
+    for (name, options) in &variants {
+        group.bench_function(*name, |b| {
+            b.iter(|| Packer::new().pack(&export, options).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_unpack(c: &mut Criterion) {
+    let corpora = vec![
+        (
+            "api_responses",
+            corpora::api_responses(),
+            PackOptions::new(),
+        ),
+        ("telemetry", corpora::telemetry(), PackOptions::new()),
+        (
+            "telemetry_numeric_sequences",
+            corpora::telemetry(),
+            numeric_sequence_options(),
+        ),
+    ];
+
+    let mut group = c.benchmark_group("unpack");
+    group.throughput(Throughput::Elements(corpora::MESSAGES as u64));
+    for (name, messages, options) in &corpora {
+        let mut packer = Packer::new();
+        let packed: Vec<Value> = messages
+            .iter()
+            .map(|message| packer.pack(message, options).unwrap())
+            .collect();
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                let mut unpacker = Unpacker::new();
+                for frame in &packed {
+                    let _unpacked: Value = unpacker.unpack(frame).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_unpack_string(c: &mut Criterion) {
+    let options = PackOptions::new();
+    let mut packer = Packer::new();
+    let packed: Vec<Value> = corpora::logs()
+        .iter()
+        .map(|log| packer.pack_string(log, &options).unwrap())
+        .collect();
+
+    let mut group = c.benchmark_group("unpack_string");
+    group.throughput(Throughput::Elements(corpora::MESSAGES as u64));
+    group.bench_function("logs", |b| {
+        b.iter(|| {
+            let mut unpacker = Unpacker::new();
+            for frame in &packed {
+                unpacker.unpack_string(frame).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_pack,
+    bench_pack_string,
+    bench_pack_export,
+    bench_unpack,
+    bench_unpack_string
+);
+criterion_main!(benches);
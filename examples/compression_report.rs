@@ -0,0 +1,84 @@
+//! Prints the packed size compared to raw JSON for each message of the benchmark corpora.
+//!
+//!     cargo run --example compression_report
+
+#[macro_use]
+extern crate serde_json;
+extern crate jsonm;
+
+#[path = "../benches/corpora/mod.rs"]
+mod corpora;
+
+use jsonm::packer::{PackOptions, Packer, StringSplitter};
+use serde_json::Value;
+
+fn report<F>(name: &str, raw_messages: Vec<String>, mut pack: F)
+where
+    F: FnMut(&str) -> Value,
+{
+    println!("{}", name);
+    println!(
+        "{:>8} {:>10} {:>10} {:>8} {:>12}",
+        "message", "raw", "packed", "ratio", "cumulative"
+    );
+
+    let mut total_raw = 0;
+    let mut total_packed = 0;
+    for (i, raw) in raw_messages.iter().enumerate() {
+        let packed = pack(raw).to_string();
+        total_raw += raw.len();
+        total_packed += packed.len();
+        if i < 5 || (i + 1) % 10 == 0 {
+            println!(
+                "{:>8} {:>10} {:>10} {:>7.1}% {:>11.1}%",
+                i,
+                raw.len(),
+                packed.len(),
+                100.0 * packed.len() as f64 / raw.len() as f64,
+                100.0 * total_packed as f64 / total_raw as f64
+            );
+        }
+    }
+    println!();
+}
+
+fn to_strings(messages: Vec<Value>) -> Vec<String> {
+    messages.iter().map(|message| message.to_string()).collect()
+}
+
+fn main() {
+    let options = PackOptions::new();
+    let mut numeric_sequence_options = PackOptions::new();
+    numeric_sequence_options.pack_numeric_sequences = true;
+
+    let mut packer = Packer::new();
+    report(
+        "api_responses",
+        to_strings(corpora::api_responses()),
+        |raw| packer.pack_string(raw, &options).unwrap(),
+    );
+
+    let mut packer = Packer::new();
+    report("logs", corpora::logs(), |raw| {
+        packer.pack_string(raw, &options).unwrap()
+    });
+
+    let mut whitespace_options = PackOptions::new();
+    whitespace_options.string_splitter = StringSplitter::Whitespace;
+    let mut packer = Packer::new();
+    report("logs (whitespace splitter)", corpora::logs(), |raw| {
+        packer.pack_string(raw, &whitespace_options).unwrap()
+    });
+
+    let mut packer = Packer::new();
+    report("telemetry", to_strings(corpora::telemetry()), |raw| {
+        packer.pack_string(raw, &options).unwrap()
+    });
+
+    let mut packer = Packer::new();
+    report(
+        "telemetry (numeric sequences)",
+        to_strings(corpora::telemetry()),
+        |raw| packer.pack_string(raw, &numeric_sequence_options).unwrap(),
+    );
+}
@@ -0,0 +1,187 @@
+#ifndef JSONM_H
+#define JSONM_H
+
+/* Generated with cbindgen from src/ffi.rs, do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/*
+ Result of a C API call. Details of the last failure on the calling thread are
+ available from `jsonm_last_error`.
+ */
+typedef enum JsonmStatus {
+  JSONM_OK = 0,
+  /*
+   A handle, input or output pointer is NULL.
+   */
+  JSONM_NULL_ARGUMENT = 1,
+  /*
+   The input is not valid UTF-8, or the output contains a NUL byte.
+   */
+  JSONM_INVALID_STRING = 2,
+  /*
+   The input is not valid JSON.
+   */
+  JSONM_INVALID_JSON = 3,
+  /*
+   The input could not be packed.
+   */
+  JSONM_PACK_FAILED = 4,
+  /*
+   The frame is not the next one in sequence. The unpacker must be resynchronised,
+   e.g. by resetting the packer.
+   */
+  JSONM_OUT_OF_SEQUENCE = 5,
+  /*
+   The frame references a dictionary entry the unpacker doesn't have.
+   */
+  JSONM_UNKNOWN_REFERENCE = 6,
+  /*
+   The frame references a schema that is not registered.
+   */
+  JSONM_UNKNOWN_SCHEMA = 7,
+  /*
+   The frame is not a valid packed message.
+   */
+  JSONM_MALFORMED_FRAME = 8,
+  /*
+   The library panicked. The handle may be inconsistent and should be released.
+   */
+  JSONM_PANIC = 9,
+} JsonmStatus;
+
+/*
+ Opaque packer handle.
+ */
+typedef struct JsonmPacker JsonmPacker;
+
+/*
+ Opaque unpacker handle.
+ */
+typedef struct JsonmUnpacker JsonmUnpacker;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ Create a packer. Must be released with `jsonm_packer_free`.
+ */
+struct JsonmPacker *jsonm_packer_new(void);
+
+/*
+ Release a packer.
+
+ # Safety
+
+ `packer` must be NULL or returned by `jsonm_packer_new` and not released yet.
+ */
+void jsonm_packer_free(struct JsonmPacker *packer);
+
+/*
+ Set the maximum dictionary size. Must match the unpacker. Default - 2000.
+
+ # Safety
+
+ `packer` must be NULL or a live handle returned by `jsonm_packer_new`.
+ */
+void jsonm_packer_set_max_dict_size(struct JsonmPacker *packer, uint64_t value);
+
+/*
+ Reset the packer dictionary and sequence, the next frame restarts the unpacker too.
+
+ # Safety
+
+ `packer` must be NULL or a live handle returned by `jsonm_packer_new`.
+ */
+void jsonm_packer_reset(struct JsonmPacker *packer);
+
+/*
+ Pack a JSON message. On success `*out` is the packed frame as JSON, to be released
+ with `jsonm_free`.
+
+ # Safety
+
+ `packer` must be a live handle, `json` a NUL-terminated string and `out` writable.
+ */
+enum JsonmStatus jsonm_pack_json(struct JsonmPacker *packer, const char *json, char **out);
+
+/*
+ Pack a string, e.g. multi-line text. Unpack it with `jsonm_unpacker_unpack_string`.
+
+ # Safety
+
+ `packer` must be a live handle, `string` a NUL-terminated string and `out` writable.
+ */
+enum JsonmStatus jsonm_pack_string(struct JsonmPacker *packer, const char *string, char **out);
+
+/*
+ Create an unpacker. Must be released with `jsonm_unpacker_free`.
+ */
+struct JsonmUnpacker *jsonm_unpacker_new(void);
+
+/*
+ Release an unpacker.
+
+ # Safety
+
+ `unpacker` must be NULL or returned by `jsonm_unpacker_new` and not released yet.
+ */
+void jsonm_unpacker_free(struct JsonmUnpacker *unpacker);
+
+/*
+ Set the maximum dictionary size. Must match the packer. Default - 2000.
+
+ # Safety
+
+ `unpacker` must be NULL or a live handle returned by `jsonm_unpacker_new`.
+ */
+void jsonm_unpacker_set_max_dict_size(struct JsonmUnpacker *unpacker, uint64_t value);
+
+/*
+ Unpack a frame produced by `jsonm_pack_json`. On success `*out` is the original
+ message as JSON, to be released with `jsonm_free`.
+
+ # Safety
+
+ `unpacker` must be a live handle, `packed` a NUL-terminated string and `out` writable.
+ */
+enum JsonmStatus jsonm_unpacker_unpack_json(struct JsonmUnpacker *unpacker,
+                                            const char *packed,
+                                            char **out);
+
+/*
+ Unpack a frame produced by `jsonm_pack_string`. On success `*out` is the original
+ string, to be released with `jsonm_free`.
+
+ # Safety
+
+ `unpacker` must be a live handle, `packed` a NUL-terminated string and `out` writable.
+ */
+enum JsonmStatus jsonm_unpacker_unpack_string(struct JsonmUnpacker *unpacker,
+                                              const char *packed,
+                                              char **out);
+
+/*
+ Release a string returned by the library.
+
+ # Safety
+
+ `string` must be NULL or returned by this library and not released yet.
+ */
+void jsonm_free(char *string);
+
+/*
+ Description of the last failure on the calling thread, or NULL. Valid until the next
+ failing call on the same thread, must not be released.
+ */
+const char *jsonm_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* JSONM_H */
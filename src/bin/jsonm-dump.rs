@@ -0,0 +1,72 @@
+//! Prints packed frames as trees. Reads one JSON frame per line from the given files,
+//! or from stdin, in the order they were packed.
+//!
+//!     jsonm-dump captured.jsonl
+extern crate jsonm;
+extern crate serde_json;
+
+use jsonm::disassembler::disassemble_frame;
+use jsonm::unpacker::Unpacker;
+use serde_json::Value;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::process;
+
+fn main() {
+    let paths: Vec<String> = env::args().skip(1).collect();
+    let mut unpacker = Unpacker::new();
+    let mut failed = false;
+
+    if paths.is_empty() {
+        let stdin = io::stdin();
+        failed |= dump(&mut unpacker, stdin.lock(), "stdin");
+    }
+    for path in &paths {
+        match File::open(path) {
+            Ok(file) => failed |= dump(&mut unpacker, BufReader::new(file), path),
+            Err(err) => {
+                eprintln!("{}: {}", path, err);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
+
+/// Returns whether any frame could not be disassembled.
+fn dump<R: BufRead>(unpacker: &mut Unpacker, reader: R, name: &str) -> bool {
+    let mut failed = false;
+    for (number, line) in reader.lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                eprintln!("{}: {}", name, err);
+                return true;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let frame: Value = match serde_json::from_str(&line) {
+            Ok(frame) => frame,
+            Err(err) => {
+                eprintln!("{}:{}: invalid JSON: {}", name, number + 1, err);
+                failed = true;
+                continue;
+            }
+        };
+        match disassemble_frame(unpacker, &frame) {
+            Ok(tree) => print!("{}", tree),
+            Err(err) => {
+                eprintln!("{}:{}: {}", name, number + 1, err.cause);
+                failed = true;
+            }
+        }
+    }
+    failed
+}
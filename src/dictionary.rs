@@ -0,0 +1,57 @@
+use serde_json::Value;
+
+/// What a dictionary entry was memoised as when it was first seen.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// An object key, a column name or a delta path key.
+    Key,
+    /// A scalar value: string, number, boolean or null.
+    #[default]
+    Value,
+    /// A whole object whose keys and values were all dictionary references, or an array
+    /// whose elements were, see `MemoPolicy::memoise_arrays`.
+    ComplexObject,
+}
+
+impl EntryKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            EntryKind::Key => "key",
+            EntryKind::Value => "value",
+            EntryKind::ComplexObject => "complex_object",
+        }
+    }
+}
+
+/// A dictionary entry of a `Packer` or an `Unpacker`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DictEntry {
+    pub index: u64,
+    pub value: Value,
+    pub kind: EntryKind,
+}
+
+/// Dictionary lookup or assignment of a single packed node, recorded while explaining a frame.
+#[derive(Debug, Clone)]
+pub(crate) struct Resolution {
+    pub new: bool,
+    pub entry: DictEntry,
+}
+
+impl Resolution {
+    /// Replace the packed node with `{"$ref"|"$new": index, "kind": .., "value": ..}`.
+    /// Packed complex objects and literals packed differently, e.g. `"~1"`, are kept
+    /// under `"packed"`.
+    pub(crate) fn annotate(self, packed: Value) -> Value {
+        let tag = if self.new { "$new" } else { "$ref" };
+        let mut annotation = json!({
+            tag: self.entry.index,
+            "kind": self.entry.kind.as_str(),
+            "value": self.entry.value,
+        });
+        if packed.is_array() || (self.new && packed != self.entry.value) {
+            annotation["packed"] = packed;
+        }
+        annotation
+    }
+}
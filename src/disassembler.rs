@@ -0,0 +1,186 @@
+use frame::{
+    DELTA_SET, TYPE_ARRAY, TYPE_COLUMNS, TYPE_DELTA, TYPE_HANDSHAKE, TYPE_NUMBERS, TYPE_PREFIX,
+    TYPE_REORDERED, TYPE_SCHEMA, TYPE_SPLIT_STRING, TYPE_STRING, TYPE_VALUE,
+};
+use serde_json::Value;
+use session::SessionConfig;
+use std::fmt::Write;
+use unpacker::{Unpacker, UnpackerError};
+
+/// Disassemble a sequence of packed frames, in the order they were packed.
+/// The unpacker must be configured like the one on the receiving side, e.g. with
+/// the same schemas, and is advanced past the frames.
+pub fn disassemble(unpacker: &mut Unpacker, frames: &[Value]) -> Result<String, UnpackerError> {
+    let mut out = String::new();
+    for frame in frames {
+        out.push_str(&disassemble_frame(unpacker, frame)?);
+    }
+    Ok(out)
+}
+
+/// Disassemble a single packed frame into a tree, one node per line:
+///
+/// ```text
+/// sequence 1
+///   TYPE_ARRAY
+///     new #5 complex_object {"foo":"bar"}
+///       OBJECT
+///         ref #3 key "foo"
+///           ref #4 value "bar"
+///     new #6 value "1" (packed "~1")
+/// ```
+///
+/// Handshake frames are applied to the unpacker and printed with their settings.
+pub fn disassemble_frame(unpacker: &mut Unpacker, frame: &Value) -> Result<String, UnpackerError> {
+    if frame.get(0).and_then(|t| t.as_i64()) == Some(TYPE_HANDSHAKE) {
+        unpacker.accept_handshake(frame)?;
+        let settings = SessionConfig::from_handshake(frame)?.to_handshake();
+        return Ok(format!("TYPE_HANDSHAKE {}\n", settings[1]));
+    }
+
+    let mut out = String::new();
+    match unpacker.unpack_explained(frame)? {
+        (body, Some(sequence_id)) => {
+            let _ = writeln!(out, "sequence {}", sequence_id);
+            write_node(&mut out, &body, 1);
+        }
+        (body, None) => write_node(&mut out, &body, 0),
+    }
+    Ok(out)
+}
+
+fn write_node(out: &mut String, node: &Value, depth: usize) {
+    match *node {
+        Value::Array(ref arr) => match arr.first().and_then(|t| t.as_i64()) {
+            Some(type_id) => write_typed(out, type_id, &arr[1..], depth),
+            None => write_object(out, arr, depth),
+        },
+        Value::Object(ref annotation) => {
+            let (tag, index) = match (annotation.get("$ref"), annotation.get("$new")) {
+                (Some(index), _) => ("ref", index),
+                (None, Some(index)) => ("new", index),
+                _ => {
+                    write_line(out, depth, &format!("literal {}", node));
+                    return;
+                }
+            };
+            let mut line = format!(
+                "{} #{} {} {}",
+                tag,
+                index,
+                annotation["kind"].as_str().unwrap_or("?"),
+                annotation["value"]
+            );
+            match annotation.get("packed") {
+                Some(packed) if packed.is_array() => {
+                    write_line(out, depth, &line);
+                    write_node(out, packed, depth + 1);
+                }
+                Some(packed) => {
+                    let _ = write!(line, " (packed {})", packed);
+                    write_line(out, depth, &line);
+                }
+                None => write_line(out, depth, &line),
+            }
+        }
+        _ => write_line(out, depth, &format!("literal {}", node)),
+    }
+}
+
+fn write_typed(out: &mut String, type_id: i64, body: &[Value], depth: usize) {
+    match type_id {
+        TYPE_ARRAY => write_children(out, "TYPE_ARRAY", body, depth),
+        TYPE_VALUE => write_children(out, "TYPE_VALUE", body, depth),
+        TYPE_STRING => write_children(out, "TYPE_STRING", body, depth),
+        TYPE_SCHEMA if !body.is_empty() => {
+            let label = format!("TYPE_SCHEMA {}", body[0]);
+            write_children(out, &label, &body[1..], depth);
+        }
+        TYPE_COLUMNS if !body.is_empty() => {
+            write_line(out, depth, "TYPE_COLUMNS");
+            let keys = body[0]
+                .as_array()
+                .map(|keys| keys.as_slice())
+                .unwrap_or(&[]);
+            for (key, column) in keys.iter().zip(&body[1..]) {
+                write_node(out, key, depth + 1);
+                for value in column.as_array().map(|c| c.as_slice()).unwrap_or(&[]) {
+                    write_node(out, value, depth + 2);
+                }
+            }
+        }
+        TYPE_DELTA => {
+            write_line(out, depth, "TYPE_DELTA");
+            for op in body {
+                write_delta_op(out, op, depth + 1);
+            }
+        }
+        TYPE_NUMBERS => {
+            let line = format!("TYPE_NUMBERS {}", Value::Array(body.to_vec()));
+            write_line(out, depth, &line);
+        }
+        TYPE_PREFIX => write_children(out, "TYPE_PREFIX", body, depth),
+        TYPE_SPLIT_STRING if !body.is_empty() => {
+            let label = format!("TYPE_SPLIT_STRING splitter {}", body[0]);
+            write_children(out, &label, &body[1..], depth);
+        }
+        TYPE_REORDERED if !body.is_empty() => {
+            let positions = Value::Array(body[1..].to_vec());
+            let line = format!("TYPE_REORDERED #{} key positions {}", body[0], positions);
+            write_line(out, depth, &line);
+        }
+        _ => write_children(out, &format!("unknown type {}", type_id), body, depth),
+    }
+}
+
+fn write_children(out: &mut String, label: &str, children: &[Value], depth: usize) {
+    write_line(out, depth, label);
+    for child in children {
+        write_node(out, child, depth + 1);
+    }
+}
+
+/// Objects are packed as all keys followed by all values, printed as key/value pairs.
+fn write_object(out: &mut String, arr: &[Value], depth: usize) {
+    write_line(out, depth, "OBJECT");
+    let key_count = arr.len() / 2;
+    for (key, value) in arr[..key_count].iter().zip(&arr[key_count..]) {
+        write_node(out, key, depth + 1);
+        write_node(out, value, depth + 2);
+    }
+}
+
+fn write_delta_op(out: &mut String, op: &Value, depth: usize) {
+    let op = match op.as_array() {
+        Some(op) if !op.is_empty() => op,
+        _ => {
+            write_line(out, depth, &format!("literal {}", op));
+            return;
+        }
+    };
+
+    let (label, segments, value) = if op[0].as_u64() == Some(DELTA_SET) && op.len() >= 2 {
+        ("SET", &op[1..op.len() - 1], Some(&op[op.len() - 1]))
+    } else {
+        ("REMOVE", &op[1..], None)
+    };
+    write_line(out, depth, label);
+    for segment in segments {
+        match segment.as_i64() {
+            Some(i) if i < 0 => write_line(out, depth + 1, &format!("[{}]", -i - 1)),
+            _ => write_node(out, segment, depth + 1),
+        }
+    }
+    if let Some(value) = value {
+        write_line(out, depth + 1, "=");
+        write_node(out, value, depth + 2);
+    }
+}
+
+fn write_line(out: &mut String, depth: usize, line: &str) {
+    for _i in 0..depth {
+        out.push_str("  ");
+    }
+    out.push_str(line);
+    out.push('\n');
+}
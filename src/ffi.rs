@@ -0,0 +1,355 @@
+//! C API built with the `ffi` feature. Packers and unpackers are opaque handles,
+//! messages are passed as NUL-terminated JSON strings. See `include/jsonm.h`.
+use packer::{PackOptions, Packer};
+use serde_json::{self, Value};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use unpacker::{Unpacker, UnpackerError, UnpackerErrorKind};
+
+/// Result of a C API call. Details of the last failure on the calling thread are
+/// available from `jsonm_last_error`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonmStatus {
+    JsonmOk = 0,
+    /// A handle, input or output pointer is NULL.
+    JsonmNullArgument = 1,
+    /// The input is not valid UTF-8, or the output contains a NUL byte.
+    JsonmInvalidString = 2,
+    /// The input is not valid JSON.
+    JsonmInvalidJson = 3,
+    /// The input could not be packed.
+    JsonmPackFailed = 4,
+    /// The frame is not the next one in sequence. The unpacker must be resynchronised,
+    /// e.g. by resetting the packer.
+    JsonmOutOfSequence = 5,
+    /// The frame references a dictionary entry the unpacker doesn't have.
+    JsonmUnknownReference = 6,
+    /// The frame references a schema that is not registered.
+    JsonmUnknownSchema = 7,
+    /// The frame is not a valid packed message.
+    JsonmMalformedFrame = 8,
+    /// The library panicked. The handle may be inconsistent and should be released.
+    JsonmPanic = 9,
+}
+
+impl From<&UnpackerError> for JsonmStatus {
+    fn from(err: &UnpackerError) -> JsonmStatus {
+        match err.kind {
+            UnpackerErrorKind::OutOfSequence => JsonmStatus::JsonmOutOfSequence,
+            UnpackerErrorKind::UnknownReference => JsonmStatus::JsonmUnknownReference,
+            UnpackerErrorKind::UnknownSchema => JsonmStatus::JsonmUnknownSchema,
+            UnpackerErrorKind::MalformedFrame
+            | UnpackerErrorKind::WrongType
+            | UnpackerErrorKind::InvalidState => JsonmStatus::JsonmMalformedFrame,
+        }
+    }
+}
+
+/// Opaque packer handle.
+pub struct JsonmPacker {
+    packer: Packer,
+    options: PackOptions,
+}
+
+/// Opaque unpacker handle.
+pub struct JsonmUnpacker {
+    unpacker: Unpacker,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn fail(status: JsonmStatus, cause: &str) -> JsonmStatus {
+    LAST_ERROR.with(|last_error| {
+        *last_error.borrow_mut() = CString::new(cause.replace('\0', "")).ok();
+    });
+    status
+}
+
+/// Run the body of a C API call, a panic must not unwind into the caller.
+/// Returns `on_panic` instead, `jsonm_last_error` reports the panic.
+fn guard<T, F>(on_panic: T, body: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(result) => result,
+        Err(_err) => {
+            fail(JsonmStatus::JsonmPanic, "jsonm panicked");
+            on_panic
+        }
+    }
+}
+
+unsafe fn read_str<'a>(input: *const c_char) -> Result<&'a str, JsonmStatus> {
+    if input.is_null() {
+        return Err(fail(JsonmStatus::JsonmNullArgument, "input is NULL"));
+    }
+    match CStr::from_ptr(input).to_str() {
+        Ok(input) => Ok(input),
+        Err(_err) => Err(fail(
+            JsonmStatus::JsonmInvalidString,
+            "input is not valid UTF-8",
+        )),
+    }
+}
+
+unsafe fn read_json(input: *const c_char) -> Result<Value, JsonmStatus> {
+    match serde_json::from_str(read_str(input)?) {
+        Ok(value) => Ok(value),
+        Err(err) => Err(fail(JsonmStatus::JsonmInvalidJson, &err.to_string())),
+    }
+}
+
+unsafe fn write_string(string: String, out: *mut *mut c_char) -> JsonmStatus {
+    match CString::new(string) {
+        Ok(string) => {
+            *out = string.into_raw();
+            JsonmStatus::JsonmOk
+        }
+        Err(_err) => fail(
+            JsonmStatus::JsonmInvalidString,
+            "output contains a NUL byte",
+        ),
+    }
+}
+
+/// Create a packer. Must be released with `jsonm_packer_free`.
+#[no_mangle]
+pub extern "C" fn jsonm_packer_new() -> *mut JsonmPacker {
+    guard(ptr::null_mut(), || {
+        Box::into_raw(Box::new(JsonmPacker {
+            packer: Packer::new(),
+            options: PackOptions::new(),
+        }))
+    })
+}
+
+/// Release a packer.
+///
+/// # Safety
+///
+/// `packer` must be NULL or returned by `jsonm_packer_new` and not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn jsonm_packer_free(packer: *mut JsonmPacker) {
+    guard((), || {
+        if !packer.is_null() {
+            drop(Box::from_raw(packer));
+        }
+    })
+}
+
+/// Set the maximum dictionary size. Must match the unpacker. Default - 2000.
+///
+/// # Safety
+///
+/// `packer` must be NULL or a live handle returned by `jsonm_packer_new`.
+#[no_mangle]
+pub unsafe extern "C" fn jsonm_packer_set_max_dict_size(packer: *mut JsonmPacker, value: u64) {
+    guard((), || {
+        if let Some(packer) = packer.as_mut() {
+            packer.packer.set_max_dict_size(value);
+        }
+    })
+}
+
+/// Reset the packer dictionary and sequence, the next frame restarts the unpacker too.
+///
+/// # Safety
+///
+/// `packer` must be NULL or a live handle returned by `jsonm_packer_new`.
+#[no_mangle]
+pub unsafe extern "C" fn jsonm_packer_reset(packer: *mut JsonmPacker) {
+    guard((), || {
+        if let Some(packer) = packer.as_mut() {
+            packer.packer.reset();
+        }
+    })
+}
+
+/// Pack a JSON message. On success `*out` is the packed frame as JSON, to be released
+/// with `jsonm_free`.
+///
+/// # Safety
+///
+/// `packer` must be a live handle, `json` a NUL-terminated string and `out` writable.
+#[no_mangle]
+pub unsafe extern "C" fn jsonm_pack_json(
+    packer: *mut JsonmPacker,
+    json: *const c_char,
+    out: *mut *mut c_char,
+) -> JsonmStatus {
+    guard(JsonmStatus::JsonmPanic, || {
+        let packer = match packer.as_mut() {
+            Some(packer) if !out.is_null() => packer,
+            _ => return fail(JsonmStatus::JsonmNullArgument, "packer or out is NULL"),
+        };
+        let value = match read_json(json) {
+            Ok(value) => value,
+            Err(status) => return status,
+        };
+
+        match packer.packer.pack(&value, &packer.options) {
+            Ok(packed) => write_string(packed.to_string(), out),
+            Err(err) => fail(JsonmStatus::JsonmPackFailed, &err.to_string()),
+        }
+    })
+}
+
+/// Pack a string, e.g. multi-line text. Unpack it with `jsonm_unpacker_unpack_string`.
+///
+/// # Safety
+///
+/// `packer` must be a live handle, `string` a NUL-terminated string and `out` writable.
+#[no_mangle]
+pub unsafe extern "C" fn jsonm_pack_string(
+    packer: *mut JsonmPacker,
+    string: *const c_char,
+    out: *mut *mut c_char,
+) -> JsonmStatus {
+    guard(JsonmStatus::JsonmPanic, || {
+        let packer = match packer.as_mut() {
+            Some(packer) if !out.is_null() => packer,
+            _ => return fail(JsonmStatus::JsonmNullArgument, "packer or out is NULL"),
+        };
+        let string = match read_str(string) {
+            Ok(string) => string,
+            Err(status) => return status,
+        };
+
+        match packer.packer.pack_string(string, &packer.options) {
+            Ok(packed) => write_string(packed.to_string(), out),
+            Err(err) => fail(JsonmStatus::JsonmPackFailed, &err.to_string()),
+        }
+    })
+}
+
+/// Create an unpacker. Must be released with `jsonm_unpacker_free`.
+#[no_mangle]
+pub extern "C" fn jsonm_unpacker_new() -> *mut JsonmUnpacker {
+    guard(ptr::null_mut(), || {
+        Box::into_raw(Box::new(JsonmUnpacker {
+            unpacker: Unpacker::new(),
+        }))
+    })
+}
+
+/// Release an unpacker.
+///
+/// # Safety
+///
+/// `unpacker` must be NULL or returned by `jsonm_unpacker_new` and not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn jsonm_unpacker_free(unpacker: *mut JsonmUnpacker) {
+    guard((), || {
+        if !unpacker.is_null() {
+            drop(Box::from_raw(unpacker));
+        }
+    })
+}
+
+/// Set the maximum dictionary size. Must match the packer. Default - 2000.
+///
+/// # Safety
+///
+/// `unpacker` must be NULL or a live handle returned by `jsonm_unpacker_new`.
+#[no_mangle]
+pub unsafe extern "C" fn jsonm_unpacker_set_max_dict_size(
+    unpacker: *mut JsonmUnpacker,
+    value: u64,
+) {
+    guard((), || {
+        if let Some(unpacker) = unpacker.as_mut() {
+            unpacker.unpacker.set_max_dict_size(value);
+        }
+    })
+}
+
+/// Unpack a frame produced by `jsonm_pack_json`. On success `*out` is the original
+/// message as JSON, to be released with `jsonm_free`.
+///
+/// # Safety
+///
+/// `unpacker` must be a live handle, `packed` a NUL-terminated string and `out` writable.
+#[no_mangle]
+pub unsafe extern "C" fn jsonm_unpacker_unpack_json(
+    unpacker: *mut JsonmUnpacker,
+    packed: *const c_char,
+    out: *mut *mut c_char,
+) -> JsonmStatus {
+    guard(JsonmStatus::JsonmPanic, || {
+        let unpacker = match unpacker.as_mut() {
+            Some(unpacker) if !out.is_null() => unpacker,
+            _ => return fail(JsonmStatus::JsonmNullArgument, "unpacker or out is NULL"),
+        };
+        let packed = match read_json(packed) {
+            Ok(packed) => packed,
+            Err(status) => return status,
+        };
+
+        match unpacker.unpacker.unpack::<Value>(&packed) {
+            Ok(unpacked) => write_string(unpacked.to_string(), out),
+            Err(err) => fail(JsonmStatus::from(&err), &err.cause),
+        }
+    })
+}
+
+/// Unpack a frame produced by `jsonm_pack_string`. On success `*out` is the original
+/// string, to be released with `jsonm_free`.
+///
+/// # Safety
+///
+/// `unpacker` must be a live handle, `packed` a NUL-terminated string and `out` writable.
+#[no_mangle]
+pub unsafe extern "C" fn jsonm_unpacker_unpack_string(
+    unpacker: *mut JsonmUnpacker,
+    packed: *const c_char,
+    out: *mut *mut c_char,
+) -> JsonmStatus {
+    guard(JsonmStatus::JsonmPanic, || {
+        let unpacker = match unpacker.as_mut() {
+            Some(unpacker) if !out.is_null() => unpacker,
+            _ => return fail(JsonmStatus::JsonmNullArgument, "unpacker or out is NULL"),
+        };
+        let packed = match read_json(packed) {
+            Ok(packed) => packed,
+            Err(status) => return status,
+        };
+
+        match unpacker.unpacker.unpack_string(&packed) {
+            Ok(unpacked) => write_string(unpacked, out),
+            Err(err) => fail(JsonmStatus::from(&err), &err.cause),
+        }
+    })
+}
+
+/// Release a string returned by the library.
+///
+/// # Safety
+///
+/// `string` must be NULL or returned by this library and not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn jsonm_free(string: *mut c_char) {
+    guard((), || {
+        if !string.is_null() {
+            drop(CString::from_raw(string));
+        }
+    })
+}
+
+/// Description of the last failure on the calling thread, or NULL. Valid until the next
+/// failing call on the same thread, must not be released.
+#[no_mangle]
+pub extern "C" fn jsonm_last_error() -> *const c_char {
+    guard(ptr::null(), || {
+        LAST_ERROR.with(|last_error| match *last_error.borrow() {
+            Some(ref cause) => cause.as_ptr(),
+            None => ptr::null(),
+        })
+    })
+}
@@ -0,0 +1,29 @@
+//! Type tags of packed frames, shared by the packer, the unpacker and the disassembler.
+//! A packed array starts with a tag, a message without one is a plain object.
+
+/// Index of the first dictionary entry, lower indexes are the type tags below.
+pub(crate) const MIN_DICT_INDEX: u64 = 3;
+
+pub(crate) const TYPE_ARRAY: i64 = 0;
+pub(crate) const TYPE_VALUE: i64 = 1;
+pub(crate) const TYPE_STRING: i64 = 2;
+pub(crate) const TYPE_SCHEMA: i64 = -1;
+pub(crate) const TYPE_COLUMNS: i64 = -2;
+pub(crate) const TYPE_DELTA: i64 = -3;
+pub(crate) const TYPE_NUMBERS: i64 = -4;
+pub(crate) const TYPE_PREFIX: i64 = -5;
+pub(crate) const TYPE_SPLIT_STRING: i64 = -6;
+pub(crate) const TYPE_HANDSHAKE: i64 = -7;
+pub(crate) const TYPE_REORDERED: i64 = -8;
+
+/// Splitter ids of a `TYPE_SPLIT_STRING` frame, see `StringSplitter`.
+pub(crate) const SPLITTER_LINES: u64 = 0;
+pub(crate) const SPLITTER_LINES_WITH_TERMINATORS: u64 = 1;
+pub(crate) const SPLITTER_WHITESPACE: u64 = 2;
+pub(crate) const SPLITTER_CSV: u64 = 3;
+pub(crate) const SPLITTER_REGEX: u64 = 4;
+pub(crate) const MAX_STRING_SPLITTER_ID: u64 = SPLITTER_REGEX;
+
+/// Operations of a `TYPE_DELTA` frame.
+pub(crate) const DELTA_SET: u64 = 0;
+pub(crate) const DELTA_REMOVE: u64 = 1;
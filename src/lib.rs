@@ -1,5 +1,31 @@
 #[macro_use]
 extern crate serde_json;
+#[cfg(feature = "metrics")]
+extern crate metrics;
+// pyo3 macros expand to `::core` paths, resolved from the crate root in the 2015 edition.
+#[cfg(feature = "python")]
+extern crate core;
+#[cfg(feature = "python")]
+extern crate pyo3;
+#[cfg(feature = "wasm")]
+extern crate serde;
+#[cfg(feature = "wasm")]
+extern crate serde_wasm_bindgen;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
 
+pub mod dictionary;
+pub mod disassembler;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod frame;
 pub mod packer;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod schema;
+pub mod session;
+pub mod shared;
+pub mod stats;
 pub mod unpacker;
+#[cfg(feature = "wasm")]
+pub mod wasm;
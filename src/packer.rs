@@ -1,31 +1,172 @@
+#[cfg(feature = "regex")]
 extern crate regex;
 extern crate serde;
 extern crate serde_json;
 
+#[cfg(feature = "regex")]
 use self::regex::Regex;
 use self::serde::Serialize;
-use serde_json::Value;
-use std::collections::HashMap;
+use dictionary::{DictEntry, EntryKind};
+#[cfg(feature = "regex")]
+use frame::SPLITTER_REGEX;
+use frame::{
+    DELTA_REMOVE, DELTA_SET, MIN_DICT_INDEX, SPLITTER_CSV, SPLITTER_LINES,
+    SPLITTER_LINES_WITH_TERMINATORS, SPLITTER_WHITESPACE, TYPE_ARRAY, TYPE_COLUMNS, TYPE_DELTA,
+    TYPE_NUMBERS, TYPE_PREFIX, TYPE_REORDERED, TYPE_SCHEMA, TYPE_SPLIT_STRING, TYPE_STRING,
+    TYPE_VALUE,
+};
+use schema::Schema;
+use serde_json::{Map, Value};
+use session::{MemoPolicy, SessionConfig};
+use stats::{json_size, Metrics, MetricsSink, PackerStats};
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fmt;
+use std::fmt::Write;
+use std::mem;
 use std::vec::Vec;
 
-const MIN_DICT_INDEX: u64 = 3;
-const TYPE_ARRAY: u32 = 0;
-const TYPE_VALUE: u32 = 1;
-const TYPE_STRING: u32 = 2;
-const MAX_PACK_COMPLEX_OBJECT_SIZE: usize = 12;
+#[cfg(feature = "rayon")]
+mod parallel;
+mod transaction;
 
-#[derive(Default)]
+pub use self::transaction::PackTransaction;
+
+#[derive(Default, Clone)]
 pub struct PackOptions {
     pub pack_string_depth: i32,
     pub no_sequence_id: bool,
+    /// Pack arrays of objects sharing the same keys column by column,
+    /// emitting the keys only once.
+    pub columnar: bool,
+    /// Pack arrays of integers using delta-of-delta and run-length encoding.
+    /// Such arrays bypass the dictionary.
+    pub pack_numeric_sequences: bool,
+    /// Split string values at the last occurrence of this separator and memoise
+    /// the head and the tail separately, e.g. `/` for URLs sharing a common path.
+    pub prefix_separator: Option<char>,
+    /// Strategy used by `pack_string` to split non-JSON strings into memoised segments.
+    pub string_splitter: StringSplitter,
+    /// Reject integers beyond ±(2^53 - 1), which JavaScript jsonm implementations can't
+    /// unpack exactly, instead of packing them. Other values are not checked. Default - false
+    pub strict: bool,
+    /// Memoise objects regardless of their key order. An object repeated with its keys in
+    /// another order is sent as the reference and the key order. Default - false
+    pub canonical_object_keys: bool,
+    /// Pack the elements of arrays at least this long on the rayon thread pool.
+    /// The output is identical to sequential packing. Default - None
+    #[cfg(feature = "rayon")]
+    pub parallel_min_array_len: Option<usize>,
+}
+
+/// Strategy used to split strings into separately memoised segments.
+#[derive(Clone, Debug, Default)]
+pub enum StringSplitter {
+    /// Split on `\n`, segments are joined with `\n` on unpack.
+    #[default]
+    Lines,
+    /// Split after each `\n`, keeping line terminators in the segments.
+    LinesWithTerminators,
+    /// Split into alternating runs of whitespace and non-whitespace characters.
+    Whitespace,
+    /// Split after each comma or newline that is not inside a quoted field.
+    Csv,
+    /// Split into the matches of the regex and the text between them.
+    #[cfg(feature = "regex")]
+    Regex(Regex),
+}
+
+impl StringSplitter {
+    fn id(&self) -> u64 {
+        match *self {
+            StringSplitter::Lines => SPLITTER_LINES,
+            StringSplitter::LinesWithTerminators => SPLITTER_LINES_WITH_TERMINATORS,
+            StringSplitter::Whitespace => SPLITTER_WHITESPACE,
+            StringSplitter::Csv => SPLITTER_CSV,
+            #[cfg(feature = "regex")]
+            StringSplitter::Regex(_) => SPLITTER_REGEX,
+        }
+    }
+
+    /// Split the string into segments. Concatenating the segments, or joining them
+    /// with `\n` for `Lines`, gives back the original string.
+    fn split<'a>(&self, string: &'a str) -> Vec<&'a str> {
+        match *self {
+            StringSplitter::Lines => string.split('\n').collect(),
+            StringSplitter::LinesWithTerminators => string.split_inclusive('\n').collect(),
+            StringSplitter::Whitespace => {
+                let mut segments = Vec::new();
+                let mut start = 0;
+                let mut in_whitespace = None;
+                for (i, c) in string.char_indices() {
+                    let is_whitespace = c.is_whitespace();
+                    if in_whitespace.is_some() && in_whitespace != Some(is_whitespace) {
+                        segments.push(&string[start..i]);
+                        start = i;
+                    }
+                    in_whitespace = Some(is_whitespace);
+                }
+                if start < string.len() {
+                    segments.push(&string[start..]);
+                }
+                segments
+            }
+            StringSplitter::Csv => {
+                let mut segments = Vec::new();
+                let mut start = 0;
+                let mut in_quotes = false;
+                for (i, c) in string.char_indices() {
+                    match c {
+                        '"' => in_quotes = !in_quotes,
+                        ',' | '\n' if !in_quotes => {
+                            segments.push(&string[start..=i]);
+                            start = i + 1;
+                        }
+                        _ => {}
+                    }
+                }
+                if start < string.len() {
+                    segments.push(&string[start..]);
+                }
+                segments
+            }
+            #[cfg(feature = "regex")]
+            StringSplitter::Regex(ref re) => {
+                let mut segments = Vec::new();
+                let mut start = 0;
+                for m in re.find_iter(string) {
+                    if m.start() > start {
+                        segments.push(&string[start..m.start()]);
+                    }
+                    if m.end() > m.start() {
+                        segments.push(m.as_str());
+                    }
+                    start = m.end();
+                }
+                if start < string.len() {
+                    segments.push(&string[start..]);
+                }
+                segments
+            }
+        }
+    }
 }
 
 #[derive(Default, Debug)]
 pub struct MemoObject {
     pub key: String,
     pub value: String,
+    pub kind: EntryKind,
+}
+
+impl MemoObject {
+    /// Strings are memoised as is, everything else as its JSON text.
+    fn to_value(&self) -> Value {
+        if self.kind != EntryKind::ComplexObject && self.key.starts_with('~') {
+            return Value::String(self.value.clone());
+        }
+        serde_json::from_str(&self.value).unwrap_or(Value::Null)
+    }
 }
 
 impl PackOptions {
@@ -33,6 +174,14 @@ impl PackOptions {
         PackOptions {
             pack_string_depth: -1,
             no_sequence_id: false,
+            columnar: false,
+            pack_numeric_sequences: false,
+            prefix_separator: None,
+            string_splitter: StringSplitter::Lines,
+            strict: false,
+            canonical_object_keys: false,
+            #[cfg(feature = "rayon")]
+            parallel_min_array_len: None,
         }
     }
 }
@@ -46,16 +195,47 @@ pub struct Packer {
     memoised_index: u64,
     sequence_id: i64,
     max_dict_size: u64,
+    memo_policy: MemoPolicy,
+    schemas: Vec<Schema>,
+    last_message: Option<Value>,
+    map_key: String,
+    stats: PackerStats,
+    measure_sizes: bool,
+    metrics: Option<MetricsSink>,
+    /// Undo journal of the message or transaction being packed.
+    journal: Option<Vec<transaction::Undo>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct PackerError {
-    cause: String,
+    pub cause: String,
+    /// JSON pointer to the value that could not be packed, empty for the whole message.
+    pub path: String,
+}
+
+impl PackerError {
+    pub(crate) fn new(cause: &str) -> PackerError {
+        PackerError {
+            cause: cause.to_owned(),
+            path: String::new(),
+        }
+    }
+
+    /// Prefix the path with the key or index of the parent value.
+    fn within(mut self, segment: &str) -> PackerError {
+        let segment = segment.replace('~', "~0").replace('/', "~1");
+        self.path = format!("/{}{}", segment, self.path);
+        self
+    }
 }
 
 impl fmt::Display for PackerError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "PackerError")
+        if self.path.is_empty() {
+            write!(f, "PackerError: {}", self.cause)
+        } else {
+            write!(f, "PackerError: {} at {}", self.cause, self.path)
+        }
     }
 }
 
@@ -79,74 +259,173 @@ impl Packer {
         }
     }
 
-    /// Pack an JSON-like object.
+    /// Pack an JSON-like object. On error the packer is left unchanged.
     pub fn pack<T>(&mut self, object: &T, options: &PackOptions) -> Result<Value, PackerError>
     where
         T: Serialize,
     {
-        let json_object = json!(object);
-        let result = self.pack_object_or_value(&json_object, options.pack_string_depth);
+        let json_object = to_json(object)?;
+        self.atomically(|packer| packer.pack_json(json_object, options))
+    }
+
+    fn pack_json(
+        &mut self,
+        json_object: Value,
+        options: &PackOptions,
+    ) -> Result<Value, PackerError> {
         if options.no_sequence_id {
-            return result;
+            return self.pack_object_or_value(&json_object, options.pack_string_depth, options);
         }
 
-        match result {
-            Ok(mut value) => {
-                self.sequence_id += 1;
-                if !value.is_array() {
-                    return Ok(json!([json!(TYPE_VALUE), value, json!(self.sequence_id)]));
-                }
-
-                match value.as_array_mut() {
-                    Some(result) => {
-                        result.push(json!(self.sequence_id));
-                        Ok(json!(result))
-                    }
-                    None => Err(PackerError {
-                        cause: "unknown".to_owned(),
-                    }),
-                }
-            }
-            Err(err) => Err(err),
-        }
+        let stats_before = self.stats;
+        let packed = self.pack_message(&json_object, options)?;
+        let raw_bytes = if self.measure_sizes {
+            json_size(&json_object)
+        } else {
+            0
+        };
+        self.set_last_message(json_object);
+        self.record_message(stats_before, raw_bytes, &packed);
+        Ok(packed)
     }
 
     /// Pack a string. Efficiently packs multi-line strings and JSON strings.
-    /// When unpacked, a string is always returned again.
+    /// When unpacked, a string is always returned again. On error the packer is left unchanged.
     pub fn pack_string(
         &mut self,
         string_to_pack: &str,
         options: &PackOptions,
     ) -> Result<Value, PackerError> {
-        match serde_json::from_str(string_to_pack) {
-            Ok(value) => self.pack::<Value>(&value, options),
-            Err(_err) => {
-                let string = string_to_pack.to_owned();
-                let lines = string.lines();
-                let mut result_vec = Vec::new();
-                for line in lines {
-                    result_vec.push(json!(line));
-                }
+        self.atomically(|packer| packer.pack_str_message(string_to_pack, options))
+    }
 
-                let mut result = match self.pack(&json!(result_vec), options) {
-                    Ok(result) => result,
-                    Err(err) => return Err(err),
+    fn pack_str_message(
+        &mut self,
+        string_to_pack: &str,
+        options: &PackOptions,
+    ) -> Result<Value, PackerError> {
+        // Only take the JSON path when it reproduces the exact same string on unpack.
+        match serde_json::from_str::<Value>(string_to_pack) {
+            Ok(ref value) if serde_json::to_string(value).is_ok_and(|s| s == string_to_pack) => {
+                self.pack(value, options)
+            }
+            _ => {
+                let stats_before = self.stats;
+                let segments: Vec<Value> = options
+                    .string_splitter
+                    .split(string_to_pack)
+                    .into_iter()
+                    .map(|segment| json!(segment))
+                    .collect();
+                // Packed like an array of plain strings, but never memoised as a whole.
+                let packed_segments = self.pack_array(&segments, -1, options)?;
+                let mut result = if options.no_sequence_id {
+                    packed_segments
+                } else {
+                    self.sequence_message(packed_segments)
                 };
 
                 let vec = match result.as_array_mut() {
                     Some(result) => result,
-                    None => {
-                        return Err(PackerError {
-                            cause: "unknown".to_owned(),
-                        })
-                    }
+                    None => return Err(PackerError::new("string segments packed as a scalar")),
                 };
 
-                vec[0] = json!(TYPE_STRING);
-                Ok(json!(vec))
+                match options.string_splitter {
+                    StringSplitter::Lines => vec[0] = json!(TYPE_STRING),
+                    ref splitter => {
+                        vec[0] = json!(TYPE_SPLIT_STRING);
+                        vec.insert(1, json!(splitter.id()));
+                    }
+                }
+                let packed = json!(vec);
+                if !options.no_sequence_id {
+                    self.set_last_message(json!(string_to_pack));
+                    self.record_message(stats_before, string_to_pack.len() as u64, &packed);
+                }
+                Ok(packed)
             }
         }
     }
+
+    /// Pack an JSON-like object as a diff against the previously packed message.
+    /// Must be unpacked with `Unpacker::unpack_delta`. On error the packer is left unchanged.
+    /// Key order is kept, an object whose keys were reordered is sent as a whole.
+    pub fn pack_delta<T>(&mut self, object: &T, options: &PackOptions) -> Result<Value, PackerError>
+    where
+        T: Serialize,
+    {
+        let json_object = to_json(object)?;
+        self.atomically(|packer| packer.pack_delta_json(json_object, options))
+    }
+
+    fn pack_delta_json(
+        &mut self,
+        json_object: Value,
+        options: &PackOptions,
+    ) -> Result<Value, PackerError> {
+        let stats_before = self.stats;
+        let mut result: Vec<Value> = vec![json!(TYPE_DELTA)];
+        let mut path: Vec<Value> = Vec::new();
+        match self.last_message.take() {
+            Some(last_message) => {
+                let diff = self.diff(&last_message, &json_object, &mut path, &mut result, options);
+                self.last_message = Some(last_message);
+                diff?;
+            }
+            None => self.push_delta_set(&json_object, &path, &mut result, options)?,
+        }
+
+        self.sequence_id += 1;
+        result.push(json!(self.sequence_id));
+        let packed = json!(result);
+        let raw_bytes = if self.measure_sizes {
+            json_size(&json_object)
+        } else {
+            0
+        };
+        self.set_last_message(json_object);
+        self.record_message(stats_before, raw_bytes, &packed);
+        Ok(packed)
+    }
+
+    /// Dictionary entries ordered by index.
+    pub fn dictionary(&self) -> Vec<DictEntry> {
+        let mut entries: Vec<DictEntry> = self
+            .memoised
+            .iter()
+            .map(|(index, memo)| DictEntry {
+                index: *index,
+                value: memo.to_value(),
+                kind: memo.kind,
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.index);
+        entries
+    }
+
+    /// Statistics collected since the packer was created.
+    pub fn stats(&self) -> PackerStats {
+        PackerStats {
+            sequence_id: self.sequence_id,
+            ..self.stats
+        }
+    }
+
+    /// Measure raw and packed message sizes in the statistics.
+    /// Costs an extra serialization of every message. Default - false
+    pub fn set_measure_sizes(&mut self, value: bool) {
+        self.measure_sizes = value;
+    }
+
+    /// Report statistics to `metrics` after every packed message. Enables size measurement.
+    pub fn set_metrics<M>(&mut self, metrics: M)
+    where
+        M: Metrics + 'static,
+    {
+        self.metrics = Some(MetricsSink(Box::new(metrics)));
+        self.measure_sizes = true;
+    }
+
     /// Reset the memoization dictionary, allowing consumption by new Unpacker instances.
     pub fn reset(&mut self) {
         self.memoised = HashMap::new();
@@ -154,6 +433,7 @@ impl Packer {
         self.memoised_object_map = HashMap::new();
         self.memoised_index = MIN_DICT_INDEX;
         self.sequence_id = -1;
+        self.last_message = None;
     }
 
     /// Set the maximum dictionary size. Must match the dictionary size used by the unpacker.
@@ -162,204 +442,505 @@ impl Packer {
         self.max_dict_size = value;
     }
 
-    fn pack_object_or_value(
+    /// Set which values are memoised. Must match the policy used by the unpacker.
+    /// Default - every value
+    pub fn set_memo_policy(&mut self, policy: MemoPolicy) {
+        self.memo_policy = policy;
+    }
+
+    /// Settings the unpacker must agree on.
+    pub fn session_config(&self) -> SessionConfig {
+        SessionConfig {
+            max_dict_size: self.max_dict_size,
+            memo_policy: self.memo_policy.clone(),
+        }
+    }
+
+    pub fn set_session_config(&mut self, config: SessionConfig) {
+        self.max_dict_size = config.max_dict_size;
+        self.memo_policy = config.memo_policy;
+    }
+
+    /// Reset the packer and return a handshake frame carrying its session config,
+    /// to be passed to `Unpacker::accept_handshake` before the next message.
+    pub fn handshake(&mut self) -> Value {
+        self.reset();
+        self.session_config().to_handshake()
+    }
+
+    /// Register a schema. Objects whose keys match the schema fields in order are packed
+    /// positionally, without their keys. The same schema must be registered on the unpacker.
+    pub fn register_schema(&mut self, schema: Schema) {
+        self.schemas.retain(|s| s.id != schema.id);
+        self.schemas.push(schema);
+    }
+
+    fn pack_message(
         &mut self,
         object: &Value,
-        pack_string_depth: i32,
+        options: &PackOptions,
     ) -> Result<Value, PackerError> {
-        if object.is_null() {
-            return Ok(Value::Null);
-        }
+        let value = self.pack_object_or_value(object, options.pack_string_depth, options)?;
+        Ok(self.sequence_message(value))
+    }
 
-        if object.is_array() {
-            let arr = match object.as_array() {
-                Some(arr) => arr,
-                None => {
-                    return Err(PackerError {
-                        cause: "unknown".to_string(),
-                    })
-                }
-            };
-            return Ok(self.pack_array(arr, pack_string_depth - 1));
+    fn sequence_message(&mut self, mut value: Value) -> Value {
+        self.sequence_id += 1;
+        match value.as_array_mut() {
+            Some(result) => {
+                result.push(json!(self.sequence_id));
+                value
+            }
+            None => json!([json!(TYPE_VALUE), value, json!(self.sequence_id)]),
         }
+    }
 
-        if object.is_string() && pack_string_depth >= 0 {
-            let obj_str = match object.as_str() {
-                Some(arr) => arr,
-                None => {
-                    return Err(PackerError {
-                        cause: "unknown".to_string(),
-                    })
-                }
-            };
-            let options = PackOptions {
-                no_sequence_id: true,
-                pack_string_depth: 0,
-            };
-            return self.pack_string(obj_str, &options);
+    fn record_message(&mut self, stats_before: PackerStats, raw_bytes: u64, packed: &Value) {
+        self.stats.messages += 1;
+        self.stats.sequence_id = self.sequence_id;
+        if self.measure_sizes {
+            self.stats.raw_bytes += raw_bytes;
+            self.stats.packed_bytes += json_size(packed);
         }
-
-        if !object.is_object() {
-            return Ok(self.pack_value(object));
+        if let Some(ref metrics) = self.metrics {
+            metrics.0.on_pack(&(self.stats - stats_before), &self.stats);
         }
+    }
 
-        return self.pack_object(object, pack_string_depth);
+    fn pack_object_or_value(
+        &mut self,
+        object: &Value,
+        pack_string_depth: i32,
+        options: &PackOptions,
+    ) -> Result<Value, PackerError> {
+        match *object {
+            Value::Null => Ok(Value::Null),
+            Value::Array(ref arr) => {
+                if options.pack_numeric_sequences {
+                    if options.strict {
+                        for (i, value) in arr.iter().enumerate() {
+                            check_strict(value).map_err(|err| err.within(&i.to_string()))?;
+                        }
+                    }
+                    if let Some(packed) = pack_numeric_sequence(arr) {
+                        return Ok(packed);
+                    }
+                }
+                if options.columnar {
+                    if let Some(keys) = homogeneous_keys(arr) {
+                        return self.pack_columns(arr, &keys, pack_string_depth - 1, options);
+                    }
+                }
+                let packed = self.pack_array(arr, pack_string_depth - 1, options)?;
+                Ok(self.try_pack_complex_array(object, packed))
+            }
+            Value::String(ref string) if pack_string_depth >= 0 => {
+                let options = PackOptions {
+                    no_sequence_id: true,
+                    pack_string_depth: 0,
+                    ..options.clone()
+                };
+                self.pack_string(string, &options)
+            }
+            Value::Object(ref obj) => self.pack_object(object, obj, pack_string_depth, options),
+            _ => self.pack_scalar(object, options),
+        }
     }
 
     fn pack_object(
         &mut self,
         object: &Value,
+        obj: &Map<String, Value>,
         pack_string_depth: i32,
+        options: &PackOptions,
     ) -> Result<Value, PackerError> {
-        let obj = match object.as_object() {
-            Some(obj) => obj,
-            None => {
-                return Err(PackerError {
-                    cause: "unknown".to_owned(),
-                })
+        if let Some(schema_id) = self.find_schema(obj) {
+            let mut results: Vec<Value> = vec![json!(TYPE_SCHEMA), json!(schema_id)];
+            for (key, value) in obj {
+                let packed = self.pack_object_value(value, pack_string_depth, options);
+                results.push(packed.map_err(|err| err.within(key))?);
             }
-        };
+            return Ok(json!(results));
+        }
+
         let mut results: Vec<Value> = Vec::new();
         for (key, _value) in obj {
-            results.push(self.pack_value(&json!(key)));
+            results.push(self.pack_str(key, EntryKind::Key));
         }
 
-        for (_key, value) in obj {
-            if value.is_object() || value.is_array() {
-                match self.pack_object_or_value(value, pack_string_depth - 1) {
-                    Ok(object) => results.push(object),
-                    Err(err) => return Err(err),
-                };
-            } else if value.is_string() {
-                if pack_string_depth > 0 {
-                    let string = match value.as_str() {
-                        Some(s) => s,
-                        None => {
-                            return Err(PackerError {
-                                cause: "unknown".to_owned(),
-                            })
-                        }
-                    };
-                    let packed_string = match self.pack_string(
-                        string,
-                        &PackOptions {
-                            no_sequence_id: true,
-                            pack_string_depth: -1,
-                        },
-                    ) {
-                        Ok(packed_string) => packed_string,
-                        Err(err) => return Err(err),
-                    };
-                    results.push(packed_string);
-                } else {
-                    results.push(self.pack_value(value));
+        for (key, value) in obj {
+            let packed = self.pack_object_value(value, pack_string_depth, options);
+            results.push(packed.map_err(|err| err.within(key))?);
+        }
+
+        Ok(self.try_pack_complex(object, None, results, options))
+    }
+
+    fn pack_object_value(
+        &mut self,
+        value: &Value,
+        pack_string_depth: i32,
+        options: &PackOptions,
+    ) -> Result<Value, PackerError> {
+        match *value {
+            Value::Object(_) | Value::Array(_) => {
+                self.pack_object_or_value(value, pack_string_depth - 1, options)
+            }
+            Value::String(ref string) if pack_string_depth > 0 => self.pack_string(
+                string,
+                &PackOptions {
+                    no_sequence_id: true,
+                    pack_string_depth: -1,
+                    ..options.clone()
+                },
+            ),
+            _ => self.pack_scalar(value, options),
+        }
+    }
+
+    fn pack_scalar(&mut self, value: &Value, options: &PackOptions) -> Result<Value, PackerError> {
+        if options.strict {
+            check_strict(value)?;
+        }
+        if let (Some(separator), Some(string)) = (options.prefix_separator, value.as_str()) {
+            if let Some(packed) = self.pack_prefixed_string(string, separator) {
+                return Ok(packed);
+            }
+        }
+
+        Ok(self.pack_value(value))
+    }
+
+    /// Packs a string as its memoised head up to the last separator and its tail.
+    /// Returns `None` when the string has no separator or is memoised as a whole.
+    fn pack_prefixed_string(&mut self, string: &str, separator: char) -> Option<Value> {
+        let split_at = string.trim_end_matches(separator).rfind(separator)? + separator.len_utf8();
+        self.set_string_map_key(string);
+        if self.memoised_map.contains_key(self.map_key.as_str()) {
+            return None;
+        }
+
+        let (head, tail) = string.split_at(split_at);
+        Some(json!([
+            json!(TYPE_PREFIX),
+            self.pack_str(head, EntryKind::Value),
+            self.pack_str(tail, EntryKind::Value)
+        ]))
+    }
+
+    fn diff(
+        &mut self,
+        previous: &Value,
+        next: &Value,
+        path: &mut Vec<Value>,
+        result: &mut Vec<Value>,
+        options: &PackOptions,
+    ) -> Result<(), PackerError> {
+        if identical(previous, next) {
+            return Ok(());
+        }
+
+        if let (Some(previous_obj), Some(next_obj)) = (previous.as_object(), next.as_object()) {
+            // Removed keys close up and new keys are appended, any other key order change
+            // sends the object as a whole.
+            let kept = previous_obj
+                .keys()
+                .filter(|key| next_obj.contains_key(*key));
+            let added = next_obj
+                .keys()
+                .filter(|key| !previous_obj.contains_key(*key));
+            if !kept.chain(added).eq(next_obj.keys()) {
+                return self.push_delta_set(next, path, result, options);
+            }
+
+            for key in previous_obj.keys() {
+                if !next_obj.contains_key(key) {
+                    path.push(json!(key));
+                    self.push_delta_remove(path, result);
+                    path.pop();
                 }
-            } else {
-                results.push(self.pack_value(value));
             }
+            for (key, value) in next_obj {
+                path.push(json!(key));
+                match previous_obj.get(key) {
+                    Some(previous_value) => {
+                        self.diff(previous_value, value, path, result, options)?
+                    }
+                    None => self.push_delta_set(value, path, result, options)?,
+                }
+                path.pop();
+            }
+            return Ok(());
+        }
+
+        if let (Some(previous_arr), Some(next_arr)) = (previous.as_array(), next.as_array()) {
+            if previous_arr.len() == next_arr.len() {
+                for (i, (previous_value, value)) in previous_arr.iter().zip(next_arr).enumerate() {
+                    path.push(json!(-(i as i64) - 1));
+                    self.diff(previous_value, value, path, result, options)?;
+                    path.pop();
+                }
+                return Ok(());
+            }
+        }
+
+        self.push_delta_set(next, path, result, options)
+    }
+
+    fn push_delta_set(
+        &mut self,
+        value: &Value,
+        path: &[Value],
+        result: &mut Vec<Value>,
+        options: &PackOptions,
+    ) -> Result<(), PackerError> {
+        let mut op: Vec<Value> = vec![json!(DELTA_SET)];
+        for segment in path {
+            op.push(self.pack_path_segment(segment));
+        }
+        match self.pack_object_value(value, options.pack_string_depth, options) {
+            Ok(packed) => op.push(packed),
+            Err(err) => {
+                // Path segments are keys, or array indexes encoded as -(index + 1).
+                return Err(path.iter().rev().fold(err, |err, segment| match *segment {
+                    Value::String(ref key) => err.within(key),
+                    _ => err.within(&(-segment.as_i64().unwrap_or(-1) - 1).to_string()),
+                }));
+            }
+        }
+        result.push(json!(op));
+        Ok(())
+    }
+
+    fn push_delta_remove(&mut self, path: &[Value], result: &mut Vec<Value>) {
+        let mut op: Vec<Value> = vec![json!(DELTA_REMOVE)];
+        for segment in path {
+            op.push(self.pack_path_segment(segment));
+        }
+        result.push(json!(op));
+    }
+
+    /// Keys are packed as regular values, array indexes as negative numbers.
+    fn pack_path_segment(&mut self, segment: &Value) -> Value {
+        if let Value::String(ref key) = *segment {
+            self.pack_str(key, EntryKind::Key)
+        } else {
+            segment.clone()
         }
+    }
 
-        return Ok(self.try_pack_complex_object(object, results));
+    fn find_schema(&self, obj: &Map<String, Value>) -> Option<u64> {
+        self.schemas
+            .iter()
+            .find(|schema| schema.matches(obj.keys()))
+            .map(|schema| schema.id)
     }
 
-    fn try_pack_complex_object(&mut self, object: &Value, results: Vec<Value>) -> Value {
-        if results.len() > MAX_PACK_COMPLEX_OBJECT_SIZE {
+    /// Memoise a packed object as a whole. `key` is the precomputed memo key, if any.
+    fn try_pack_complex(
+        &mut self,
+        object: &Value,
+        key: Option<String>,
+        results: Vec<Value>,
+        options: &PackOptions,
+    ) -> Value {
+        if !self.memo_policy.memoises_complex(&results) {
             return json!(results);
         }
+        if !options.canonical_object_keys {
+            return self.pack_complex(key.unwrap_or_else(|| object.to_string()), json!(results));
+        }
 
-        for v in &results {
-            if !v.is_number() {
-                return json!(results);
+        let key = key.unwrap_or_else(|| canonical_key(object));
+        if let Some(&index) = self.memoised_object_map.get(&key) {
+            self.stats.complex_object_hits += 1;
+            return self.reordered_reference(index, object);
+        }
+
+        // Memoised with its actual key order, the one the unpacker stores.
+        self.memoise(&object.to_string(), &key, EntryKind::ComplexObject);
+        json!(results)
+    }
+
+    /// Reference a memoised object, followed by the position of each key in the memoised
+    /// object when they are in another order: `[TYPE_REORDERED, index, positions...]`.
+    fn reordered_reference(&self, index: u64, object: &Value) -> Value {
+        let memoised: Map<String, Value> = match self.memoised.get(&index) {
+            Some(memo) => serde_json::from_str(&memo.value).unwrap_or_default(),
+            None => Map::new(),
+        };
+        let memoised_keys: Vec<&String> = memoised.keys().collect();
+        let positions: Option<Vec<usize>> = match object.as_object() {
+            Some(obj) => obj
+                .keys()
+                .map(|key| memoised_keys.iter().position(|k| *k == key))
+                .collect(),
+            None => None,
+        };
+
+        match positions {
+            Some(ref positions) if positions.iter().enumerate().any(|(i, p)| i != *p) => {
+                let mut result = vec![json!(TYPE_REORDERED), json!(index)];
+                result.extend(positions.iter().map(|p| json!(p)));
+                json!(result)
             }
+            _ => json!(index),
         }
+    }
 
-        let key = object.to_string();
-        if self.memoised_object_map.contains_key(&key) {
-            let val = self.memoised_object_map.get(&key);
-            return json!(val);
+    /// Memoise a packed array as a whole under the same rules as objects.
+    /// Mirrored by `Unpacker::unpack_array`.
+    fn try_pack_complex_array(&mut self, array: &Value, packed: Value) -> Value {
+        if !self.memo_policy.memoise_arrays {
+            return packed;
         }
+        match packed.as_array() {
+            Some(results) if self.memo_policy.memoises_complex(&results[1..]) => {}
+            _ => return packed,
+        }
+        self.pack_complex(array.to_string(), packed)
+    }
 
-        self.memoise(&object.to_string(), &key, true);
+    fn pack_complex(&mut self, key: String, packed: Value) -> Value {
+        if let Some(index) = self.memoised_object_map.get(&key) {
+            self.stats.complex_object_hits += 1;
+            return json!(index);
+        }
+
+        self.memoise(&key, &key, EntryKind::ComplexObject);
 
-        return json!(results);
+        packed
     }
 
-    fn pack_array(&mut self, object: &[Value], pack_string_depth: i32) -> Value {
+    fn pack_array(
+        &mut self,
+        object: &[Value],
+        pack_string_depth: i32,
+        options: &PackOptions,
+    ) -> Result<Value, PackerError> {
+        #[cfg(feature = "rayon")]
+        {
+            if let Some(min_len) = options.parallel_min_array_len {
+                if object.len() >= min_len {
+                    return self.pack_array_parallel(object, pack_string_depth, options);
+                }
+            }
+        }
+
         let mut result: Vec<Value> = Vec::new();
         result.push(json!(TYPE_ARRAY));
-        for val in object {
-            match self.pack_object_or_value(val, pack_string_depth) {
-                Ok(packed_object) => result.push(packed_object),
-                Err(_err) => {}
+        for (i, val) in object.iter().enumerate() {
+            let packed = self.pack_object_or_value(val, pack_string_depth, options);
+            result.push(packed.map_err(|err| err.within(&i.to_string()))?);
+        }
+
+        Ok(json!(result))
+    }
+
+    fn pack_columns(
+        &mut self,
+        rows: &[Value],
+        keys: &[String],
+        pack_string_depth: i32,
+        options: &PackOptions,
+    ) -> Result<Value, PackerError> {
+        let mut result: Vec<Value> = Vec::new();
+        result.push(json!(TYPE_COLUMNS));
+        let packed_keys: Vec<Value> = keys
+            .iter()
+            .map(|key| self.pack_str(key, EntryKind::Key))
+            .collect();
+        result.push(json!(packed_keys));
+
+        for key in keys {
+            let mut column: Vec<Value> = Vec::new();
+            for (i, row) in rows.iter().enumerate() {
+                let packed = self.pack_object_value(&row[key], pack_string_depth, options);
+                column.push(packed.map_err(|err| err.within(key).within(&i.to_string()))?);
             }
+            result.push(json!(column));
         }
 
-        json!(result)
+        Ok(json!(result))
     }
 
     fn pack_value(&mut self, value: &Value) -> Value {
-        let string = value.to_string();
-        let str_value: &str = match value.as_str() {
-            Some(v) => v,
-            None => string.as_str(),
-        };
-
-        let map_key_string = "~".to_owned() + str_value;
-        let map_key = if value.is_string() {
-            map_key_string.as_str()
-        } else {
-            str_value
+        let string = match *value {
+            Value::String(ref string) => string,
+            _ => return self.pack_literal(value),
         };
+        self.pack_str(string, EntryKind::Value)
+    }
 
-        if self.memoised_map.contains_key(map_key) {
-            let val = self.memoised_map.get(map_key);
-            return json!(val);
+    fn pack_str(&mut self, string: &str, kind: EntryKind) -> Value {
+        // Strings are memoised under a `~` prefix to keep them apart from numbers.
+        self.set_string_map_key(string);
+        if let Some(index) = self.memoised_map.get(self.map_key.as_str()) {
+            self.stats.dict_hits += 1;
+            return json!(index);
         }
 
-        if value.is_boolean() || value.is_null() {
-            self.memoise(str_value, map_key, false);
-            return json!(value);
+        if self.memoises(string, true, kind) {
+            self.stats.dict_misses += 1;
+            let map_key = mem::take(&mut self.map_key);
+            self.memoise(string, &map_key, kind);
+            self.map_key = map_key;
         }
 
-        if value.is_number() {
-            self.memoise(str_value, map_key, false);
-            return json!(str_value);
+        if needs_escape(string) {
+            return Value::String(self.map_key.clone());
         }
+        Value::String(string.to_owned())
+    }
 
-        if value.is_string() {
-            self.memoise(str_value, map_key, false);
-            let re = Regex::new(r"^[0-9.]|^~").unwrap();
-            if re.is_match(str_value) {
-                return json!("~".to_owned() + str_value);
-            }
-        }
+    fn pack_literal(&mut self, value: &Value) -> Value {
+        let mut map_key = mem::take(&mut self.map_key);
+        map_key.clear();
+        let _ = write!(map_key, "{}", value);
 
-        return json!(str_value);
+        let packed = match self.memoised_map.get(map_key.as_str()) {
+            Some(index) => {
+                self.stats.dict_hits += 1;
+                json!(index)
+            }
+            None => {
+                if self.memoises(&map_key, false, EntryKind::Value) {
+                    self.stats.dict_misses += 1;
+                    self.memoise(&map_key, &map_key, EntryKind::Value);
+                }
+                if value.is_number() {
+                    Value::String(map_key.clone())
+                } else {
+                    value.clone()
+                }
+            }
+        };
+        self.map_key = map_key;
+        packed
     }
 
-    fn memoise(&mut self, str_value: &str, map_key: &str, is_object: bool) {
-        match self.memoised.get(&self.memoised_index) {
-            Some(found_object) => {
-                let key = &found_object.key;
-                self.memoised_map.remove(key);
-                self.memoised_object_map.remove(key);
-            }
-            None => (),
+    /// Whether a new value is worth a dictionary slot. Mirrored by `Unpacker::unpack_scalar`.
+    fn memoises(&self, str_value: &str, is_string: bool, kind: EntryKind) -> bool {
+        match kind {
+            EntryKind::Value if is_string => self.memo_policy.memoises_string(str_value),
+            EntryKind::Value => self.memo_policy.memoises_literal(str_value),
+            _ => true,
         }
+    }
 
-        if is_object {
-            self.memoised_object_map
-                .insert(map_key.to_owned(), self.memoised_index);
-        } else {
-            self.memoised_map
-                .insert(map_key.to_owned(), self.memoised_index);
-        }
+    fn set_string_map_key(&mut self, string: &str) {
+        self.map_key.clear();
+        self.map_key.push('~');
+        self.map_key.push_str(string);
+    }
 
-        self.memoised.insert(
-            self.memoised_index,
+    fn memoise(&mut self, str_value: &str, map_key: &str, kind: EntryKind) {
+        self.write_entry(
+            map_key,
             MemoObject {
                 key: map_key.to_owned(),
                 value: str_value.to_owned(),
+                kind,
             },
         );
         self.memoised_index += 1;
@@ -369,3 +950,138 @@ impl Packer {
         }
     }
 }
+
+fn to_json<T: Serialize>(object: &T) -> Result<Value, PackerError> {
+    match serde_json::to_value(object) {
+        Ok(value) => Ok(value),
+        Err(err) => Err(PackerError::new(&format!("unable to serialize: {}", err))),
+    }
+}
+
+/// Equality including the key order of objects, which `==` ignores.
+fn identical(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b.iter())
+                    .all(|((a_key, a_value), (b_key, b_value))| {
+                        a_key == b_key && identical(a_value, b_value)
+                    })
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| identical(a, b))
+        }
+        _ => a == b,
+    }
+}
+
+/// Identity of an object regardless of its key order: its entries sorted by key.
+/// Nested values are kept as they are. Prefixed so it never matches the plain key of an
+/// object memoised without `canonical_object_keys`, whose key order must be kept.
+fn canonical_key(object: &Value) -> String {
+    let sorted = match *object {
+        Value::Object(ref obj) => {
+            let sorted: BTreeMap<&String, &Value> = obj.iter().collect();
+            serde_json::to_string(&sorted).unwrap_or_else(|_| object.to_string())
+        }
+        _ => object.to_string(),
+    };
+    format!("~{}", sorted)
+}
+
+/// Largest integer exactly representable as a double, the only number type of JavaScript.
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+fn check_strict(value: &Value) -> Result<(), PackerError> {
+    let number = match *value {
+        Value::Number(ref number) => number,
+        _ => return Ok(()),
+    };
+    let exceeds = match (number.as_u64(), number.as_i64()) {
+        (Some(n), _) => n > MAX_SAFE_INTEGER,
+        (None, Some(n)) => n.unsigned_abs() > MAX_SAFE_INTEGER,
+        (None, None) => false,
+    };
+    if exceeds {
+        return Err(PackerError::new(&format!(
+            "integer {} is not exactly representable as a double",
+            number
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the shared keys when the array holds at least two non-empty objects
+/// with the same keys in the same order.
+fn homogeneous_keys(rows: &[Value]) -> Option<Vec<String>> {
+    if rows.len() < 2 {
+        return None;
+    }
+
+    let keys: Vec<String> = match rows[0].as_object() {
+        Some(obj) if !obj.is_empty() => obj.keys().cloned().collect(),
+        _ => return None,
+    };
+
+    for row in &rows[1..] {
+        match row.as_object() {
+            Some(obj) if obj.len() == keys.len() && obj.keys().zip(&keys).all(|(a, b)| a == b) => {}
+            _ => return None,
+        }
+    }
+
+    Some(keys)
+}
+
+/// Encodes an array of at least two integers as its first value and first delta,
+/// followed by `[delta of delta, repeat count]` pairs.
+/// Returns `None` for other arrays or when the deltas overflow.
+fn pack_numeric_sequence(values: &[Value]) -> Option<Value> {
+    if values.len() < 2 {
+        return None;
+    }
+
+    let mut numbers: Vec<i64> = Vec::with_capacity(values.len());
+    for value in values {
+        numbers.push(value.as_i64()?);
+    }
+
+    let mut delta = numbers[1].checked_sub(numbers[0])?;
+    let mut result: Vec<Value> = vec![json!(TYPE_NUMBERS), json!(numbers[0]), json!(delta)];
+    let mut run: Option<(i64, u64)> = None;
+    for pair in numbers[1..].windows(2) {
+        let next_delta = pair[1].checked_sub(pair[0])?;
+        let delta_of_delta = next_delta.checked_sub(delta)?;
+        delta = next_delta;
+        run = match run {
+            Some((dod, count)) if dod == delta_of_delta => Some((dod, count + 1)),
+            Some((dod, count)) => {
+                result.push(json!(dod));
+                result.push(json!(count));
+                Some((delta_of_delta, 1))
+            }
+            None => Some((delta_of_delta, 1)),
+        };
+    }
+    if let Some((dod, count)) = run {
+        result.push(json!(dod));
+        result.push(json!(count));
+    }
+
+    Some(json!(result))
+}
+
+/// Strings that would be unpacked as numbers, or that start with the escape character.
+fn needs_escape(string: &str) -> bool {
+    let bytes = string.as_bytes();
+    let number = match bytes.first() {
+        Some(&b'~') => return true,
+        Some(&b'-') => &bytes[1..],
+        _ => bytes,
+    };
+    match number.first() {
+        Some(c) => c.is_ascii_digit() || *c == b'.',
+        None => false,
+    }
+}
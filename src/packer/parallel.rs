@@ -0,0 +1,268 @@
+//! Parallel packing of large arrays, enabled with the `rayon` feature.
+//!
+//! Elements are packed in two phases. `prepare` walks each element in parallel without
+//! touching the dictionary, computing memo keys, packed literals and complex object keys.
+//! `emit` then replays the prepared elements in order against the dictionary, so indexes
+//! are assigned exactly as the sequential algorithm does. Anything whose packing depends
+//! on the dictionary state is deferred to the sequential code at the same position.
+extern crate rayon;
+
+use self::rayon::prelude::*;
+use super::{
+    canonical_key, check_strict, needs_escape, pack_numeric_sequence, PackOptions, Packer,
+    PackerError, TYPE_ARRAY,
+};
+use dictionary::EntryKind;
+use serde_json::{Map, Value};
+
+enum Prepared<'a> {
+    /// Packed without touching the dictionary.
+    Verbatim(Value),
+    /// Packed by `pack_object_or_value` when emitted.
+    DeferredObjectOrValue(&'a Value, i32),
+    /// Packed by `pack_object_value` when emitted.
+    DeferredObjectValue(&'a Value, i32),
+    Scalar {
+        map_key: String,
+        is_string: bool,
+        kind: EntryKind,
+        /// Packed value when not memoised yet.
+        miss: Value,
+    },
+    Array {
+        /// The array when nested, memoised as a whole when emitted.
+        array: Option<&'a Value>,
+        elements: Vec<Prepared<'a>>,
+    },
+    Object {
+        object: &'a Map<String, Value>,
+        keys: Vec<Prepared<'a>>,
+        values: Vec<Prepared<'a>>,
+        /// The object when small enough to be memoised as a whole, serialized upfront
+        /// unless it contains arrays or objects, which rarely pack to references only.
+        complex: Option<(&'a Value, Option<String>)>,
+    },
+}
+
+impl Packer {
+    /// Pack array elements in parallel, producing the same output as `pack_array`.
+    pub(super) fn pack_array_parallel(
+        &mut self,
+        object: &[Value],
+        pack_string_depth: i32,
+        options: &PackOptions,
+    ) -> Result<Value, PackerError> {
+        let elements = self.prepare_elements(object, pack_string_depth, options);
+        self.emit(
+            Prepared::Array {
+                array: None,
+                elements,
+            },
+            options,
+        )
+    }
+
+    fn prepare_elements<'a>(
+        &self,
+        object: &'a [Value],
+        pack_string_depth: i32,
+        options: &PackOptions,
+    ) -> Vec<Prepared<'a>> {
+        match options.parallel_min_array_len {
+            Some(min_len) if object.len() >= min_len => object
+                .par_iter()
+                .map(|val| self.prepare_object_or_value(val, pack_string_depth, options))
+                .collect(),
+            _ => object
+                .iter()
+                .map(|val| self.prepare_object_or_value(val, pack_string_depth, options))
+                .collect(),
+        }
+    }
+
+    /// Mirrors `pack_object_or_value`.
+    fn prepare_object_or_value<'a>(
+        &self,
+        object: &'a Value,
+        pack_string_depth: i32,
+        options: &PackOptions,
+    ) -> Prepared<'a> {
+        match *object {
+            Value::Null => Prepared::Verbatim(Value::Null),
+            Value::Array(ref arr) => {
+                if options.pack_numeric_sequences {
+                    // Strict violations are reported by the sequential code, with their path.
+                    if options.strict && arr.iter().any(|v| check_strict(v).is_err()) {
+                        return Prepared::DeferredObjectOrValue(object, pack_string_depth);
+                    }
+                    if let Some(packed) = pack_numeric_sequence(arr) {
+                        return Prepared::Verbatim(packed);
+                    }
+                }
+                if options.columnar {
+                    return Prepared::DeferredObjectOrValue(object, pack_string_depth);
+                }
+                Prepared::Array {
+                    array: Some(object),
+                    elements: self.prepare_elements(arr, pack_string_depth - 1, options),
+                }
+            }
+            Value::String(_) if pack_string_depth >= 0 => {
+                Prepared::DeferredObjectOrValue(object, pack_string_depth)
+            }
+            Value::Object(ref obj) => {
+                if self.find_schema(obj).is_some() {
+                    return Prepared::DeferredObjectOrValue(object, pack_string_depth);
+                }
+                let keys = obj
+                    .keys()
+                    .map(|key| prepare_str(key, EntryKind::Key))
+                    .collect();
+                let values = obj
+                    .values()
+                    .map(|value| self.prepare_object_value(value, pack_string_depth, options))
+                    .collect();
+                let complex = if obj.len() * 2 > self.memo_policy.max_complex_object_size {
+                    None
+                } else if obj.values().any(|v| v.is_array() || v.is_object()) {
+                    Some((object, None))
+                } else if options.canonical_object_keys {
+                    Some((object, Some(canonical_key(object))))
+                } else {
+                    Some((object, Some(object.to_string())))
+                };
+                Prepared::Object {
+                    object: obj,
+                    keys,
+                    values,
+                    complex,
+                }
+            }
+            _ => self.prepare_scalar(object, pack_string_depth, options),
+        }
+    }
+
+    /// Mirrors `pack_object_value`.
+    fn prepare_object_value<'a>(
+        &self,
+        value: &'a Value,
+        pack_string_depth: i32,
+        options: &PackOptions,
+    ) -> Prepared<'a> {
+        if value.is_object() || value.is_array() {
+            return self.prepare_object_or_value(value, pack_string_depth - 1, options);
+        }
+        if value.is_string() && pack_string_depth > 0 {
+            return Prepared::DeferredObjectValue(value, pack_string_depth);
+        }
+        self.prepare_scalar(value, pack_string_depth, options)
+    }
+
+    /// Mirrors `pack_scalar`. Prefixed strings depend on the dictionary and are deferred.
+    fn prepare_scalar<'a>(
+        &self,
+        value: &'a Value,
+        pack_string_depth: i32,
+        options: &PackOptions,
+    ) -> Prepared<'a> {
+        match *value {
+            Value::String(_) if options.prefix_separator.is_some() => {
+                Prepared::DeferredObjectValue(value, pack_string_depth)
+            }
+            Value::String(ref string) => prepare_str(string, EntryKind::Value),
+            _ if options.strict && check_strict(value).is_err() => {
+                Prepared::DeferredObjectValue(value, pack_string_depth)
+            }
+            _ => {
+                let map_key = value.to_string();
+                let miss = if value.is_number() {
+                    Value::String(map_key.clone())
+                } else {
+                    value.clone()
+                };
+                Prepared::Scalar {
+                    map_key,
+                    is_string: false,
+                    kind: EntryKind::Value,
+                    miss,
+                }
+            }
+        }
+    }
+
+    fn emit(&mut self, prepared: Prepared, options: &PackOptions) -> Result<Value, PackerError> {
+        match prepared {
+            Prepared::Verbatim(value) => Ok(value),
+            Prepared::DeferredObjectOrValue(value, pack_string_depth) => {
+                self.pack_object_or_value(value, pack_string_depth, options)
+            }
+            Prepared::DeferredObjectValue(value, pack_string_depth) => {
+                self.pack_object_value(value, pack_string_depth, options)
+            }
+            Prepared::Scalar {
+                map_key,
+                is_string,
+                kind,
+                miss,
+            } => {
+                if let Some(index) = self.memoised_map.get(map_key.as_str()) {
+                    self.stats.dict_hits += 1;
+                    return Ok(json!(index));
+                }
+                let str_value = if is_string { &map_key[1..] } else { &map_key };
+                if self.memoises(str_value, is_string, kind) {
+                    self.stats.dict_misses += 1;
+                    self.memoise(str_value, &map_key, kind);
+                }
+                Ok(miss)
+            }
+            Prepared::Array { array, elements } => {
+                let mut result: Vec<Value> = Vec::with_capacity(elements.len() + 1);
+                result.push(json!(TYPE_ARRAY));
+                for (i, element) in elements.into_iter().enumerate() {
+                    let packed = self.emit(element, options);
+                    result.push(packed.map_err(|err| err.within(&i.to_string()))?);
+                }
+                match array {
+                    Some(array) => Ok(self.try_pack_complex_array(array, json!(result))),
+                    None => Ok(json!(result)),
+                }
+            }
+            Prepared::Object {
+                object,
+                keys,
+                values,
+                complex,
+            } => {
+                let mut results: Vec<Value> = Vec::with_capacity(keys.len() * 2);
+                for key in keys {
+                    results.push(self.emit(key, options)?);
+                }
+                for (key, value) in object.keys().zip(values) {
+                    let packed = self.emit(value, options);
+                    results.push(packed.map_err(|err| err.within(key))?);
+                }
+                match complex {
+                    Some((object, key)) => Ok(self.try_pack_complex(object, key, results, options)),
+                    None => Ok(json!(results)),
+                }
+            }
+        }
+    }
+}
+
+/// Mirrors `pack_str`.
+fn prepare_str<'a>(string: &str, kind: EntryKind) -> Prepared<'a> {
+    let map_key = format!("~{}", string);
+    let miss = if needs_escape(string) {
+        Value::String(map_key.clone())
+    } else {
+        Value::String(string.to_owned())
+    };
+    Prepared::Scalar {
+        map_key,
+        is_string: true,
+        kind,
+        miss,
+    }
+}
@@ -0,0 +1,221 @@
+//! Atomic packing. While a message is packed, every dictionary change is recorded in
+//! an undo journal, so a message that fails halfway, or a frame the caller decides not
+//! to send, leaves the packer exactly as the unpacker last saw it.
+use super::serde::Serialize;
+use super::{MemoObject, PackOptions, Packer, PackerError};
+use dictionary::EntryKind;
+use serde_json::Value;
+use stats::PackerStats;
+use std::collections::HashMap;
+
+/// A single reverted change of the packer state.
+#[derive(Debug)]
+pub(super) enum Undo {
+    /// A dictionary entry was written at `index`, evicting the previous entry if any.
+    Memoise {
+        index: u64,
+        evicted: Option<MemoObject>,
+        /// Index removed from the key map of the evicted entry.
+        evicted_index: Option<u64>,
+        /// Index previously mapped to the new key.
+        replaced: Option<u64>,
+    },
+    /// The previously packed message was replaced.
+    LastMessage(Option<Value>),
+}
+
+/// Packer state to roll back to.
+#[derive(Debug)]
+pub(super) struct Savepoint {
+    journal_len: usize,
+    /// Whether this savepoint started the journal, and ends it when released.
+    opened: bool,
+    memoised_index: u64,
+    sequence_id: i64,
+    stats: PackerStats,
+}
+
+/// Messages packed within a transaction are kept only once it is committed.
+/// Aborting it, or dropping it without a commit, rolls the dictionary, sequence id and
+/// statistics back to where they were when the transaction started.
+/// Statistics already reported to `Metrics` are not retracted.
+#[derive(Debug)]
+pub struct PackTransaction<'a> {
+    packer: &'a mut Packer,
+    savepoint: Option<Savepoint>,
+}
+
+impl<'a> PackTransaction<'a> {
+    /// See `Packer::pack`.
+    pub fn pack<T>(&mut self, object: &T, options: &PackOptions) -> Result<Value, PackerError>
+    where
+        T: Serialize,
+    {
+        self.packer.pack(object, options)
+    }
+
+    /// See `Packer::pack_string`.
+    pub fn pack_string(
+        &mut self,
+        string: &str,
+        options: &PackOptions,
+    ) -> Result<Value, PackerError> {
+        self.packer.pack_string(string, options)
+    }
+
+    /// See `Packer::pack_delta`.
+    pub fn pack_delta<T>(&mut self, object: &T, options: &PackOptions) -> Result<Value, PackerError>
+    where
+        T: Serialize,
+    {
+        self.packer.pack_delta(object, options)
+    }
+
+    /// Keep the packed messages. Call once their frames were sent.
+    pub fn commit(mut self) {
+        if let Some(savepoint) = self.savepoint.take() {
+            self.packer.release(savepoint);
+        }
+    }
+
+    /// Discard the packed messages. Their frames must not be sent.
+    pub fn abort(mut self) {
+        if let Some(savepoint) = self.savepoint.take() {
+            self.packer.rollback(savepoint);
+        }
+    }
+}
+
+impl<'a> Drop for PackTransaction<'a> {
+    fn drop(&mut self) {
+        if let Some(savepoint) = self.savepoint.take() {
+            self.packer.rollback(savepoint);
+        }
+    }
+}
+
+impl Packer {
+    /// Start a transaction. Messages packed through it are rolled back unless committed.
+    pub fn transaction(&mut self) -> PackTransaction<'_> {
+        let savepoint = self.savepoint();
+        PackTransaction {
+            packer: self,
+            savepoint: Some(savepoint),
+        }
+    }
+
+    /// Run `pack`, rolling back everything it changed when it fails.
+    pub(super) fn atomically<F>(&mut self, pack: F) -> Result<Value, PackerError>
+    where
+        F: FnOnce(&mut Packer) -> Result<Value, PackerError>,
+    {
+        let savepoint = self.savepoint();
+        let result = pack(self);
+        match result {
+            Ok(_) => self.release(savepoint),
+            Err(_) => self.rollback(savepoint),
+        }
+        result
+    }
+
+    pub(super) fn set_last_message(&mut self, message: Value) {
+        let previous = self.last_message.replace(message);
+        if let Some(ref mut journal) = self.journal {
+            journal.push(Undo::LastMessage(previous));
+        }
+    }
+
+    /// Write a dictionary entry at the current index, journaling what it replaces.
+    pub(super) fn write_entry(&mut self, map_key: &str, memo: MemoObject) {
+        let index = self.memoised_index;
+        let mut evicted_index = None;
+        if let Some(found_object) = self.memoised.get(&index) {
+            self.stats.evictions += 1;
+            let (key, kind) = (found_object.key.clone(), found_object.kind);
+            evicted_index = self.key_map(kind).remove(&key);
+        }
+
+        let replaced = self.key_map(memo.kind).insert(map_key.to_owned(), index);
+        let evicted = self.memoised.insert(index, memo);
+
+        if let Some(ref mut journal) = self.journal {
+            journal.push(Undo::Memoise {
+                index,
+                evicted,
+                evicted_index,
+                replaced,
+            });
+        }
+    }
+
+    /// Complex objects are looked up apart from keys and values, their keys never collide.
+    fn key_map(&mut self, kind: EntryKind) -> &mut HashMap<String, u64> {
+        if kind == EntryKind::ComplexObject {
+            &mut self.memoised_object_map
+        } else {
+            &mut self.memoised_map
+        }
+    }
+
+    fn savepoint(&mut self) -> Savepoint {
+        let opened = self.journal.is_none();
+        if opened {
+            self.journal = Some(Vec::new());
+        }
+        Savepoint {
+            journal_len: self.journal.as_ref().map_or(0, |journal| journal.len()),
+            opened,
+            memoised_index: self.memoised_index,
+            sequence_id: self.sequence_id,
+            stats: self.stats,
+        }
+    }
+
+    fn release(&mut self, savepoint: Savepoint) {
+        if savepoint.opened {
+            self.journal = None;
+        }
+    }
+
+    fn rollback(&mut self, savepoint: Savepoint) {
+        let mut journal = self.journal.take().unwrap_or_default();
+        while journal.len() > savepoint.journal_len {
+            match journal.pop() {
+                Some(undo) => self.undo(undo),
+                None => break,
+            }
+        }
+        if !savepoint.opened {
+            self.journal = Some(journal);
+        }
+        self.memoised_index = savepoint.memoised_index;
+        self.sequence_id = savepoint.sequence_id;
+        self.stats = savepoint.stats;
+    }
+
+    fn undo(&mut self, undo: Undo) {
+        match undo {
+            Undo::Memoise {
+                index,
+                evicted,
+                evicted_index,
+                replaced,
+            } => {
+                if let Some(memo) = self.memoised.remove(&index) {
+                    let map = self.key_map(memo.kind);
+                    match replaced {
+                        Some(previous) => map.insert(memo.key, previous),
+                        None => map.remove(&memo.key),
+                    };
+                }
+                if let Some(memo) = evicted {
+                    if let Some(previous) = evicted_index {
+                        self.key_map(memo.kind).insert(memo.key.clone(), previous);
+                    }
+                    self.memoised.insert(index, memo);
+                }
+            }
+            Undo::LastMessage(message) => self.last_message = message,
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! `Packer` and `Unpacker` classes for Python, built with the `python` feature:
+//!
+//! ```text
+//! maturin build --release
+//! ```
+//!
+//! Messages are native Python values: dicts, lists, strings, numbers, booleans and None.
+use packer::{PackOptions, Packer};
+use pyo3::exceptions::{PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict, PyFloat, PyInt, PyList, PyString, PyTuple};
+use serde_json::{Map, Number, Value};
+use unpacker::Unpacker;
+
+/// Convert a Python value to JSON. Dict keys must be strings.
+pub fn to_value(object: &Bound<'_, PyAny>) -> PyResult<Value> {
+    if object.is_none() {
+        return Ok(Value::Null);
+    }
+    // `bool` is a subclass of `int`, check it first.
+    if object.is_instance_of::<PyBool>() {
+        return Ok(Value::Bool(object.extract()?));
+    }
+    if object.is_instance_of::<PyInt>() {
+        if let Ok(number) = object.extract::<i64>() {
+            return Ok(Value::Number(Number::from(number)));
+        }
+        return match object.extract::<u64>() {
+            Ok(number) => Ok(Value::Number(Number::from(number))),
+            Err(_err) => Err(PyValueError::new_err("integer out of range")),
+        };
+    }
+    if object.is_instance_of::<PyFloat>() {
+        return match Number::from_f64(object.extract()?) {
+            Some(number) => Ok(Value::Number(number)),
+            None => Err(PyValueError::new_err("NaN and infinity can't be packed")),
+        };
+    }
+    if object.is_instance_of::<PyString>() {
+        return Ok(Value::String(object.extract()?));
+    }
+    if object.is_instance_of::<PyList>() || object.is_instance_of::<PyTuple>() {
+        let mut array = Vec::new();
+        for item in object.try_iter()? {
+            array.push(to_value(&item?)?);
+        }
+        return Ok(Value::Array(array));
+    }
+    if object.is_instance_of::<PyDict>() {
+        let mut map = Map::new();
+        for item in object.call_method0("items")?.try_iter()? {
+            let (key, value): (Bound<'_, PyAny>, Bound<'_, PyAny>) = item?.extract()?;
+            if !key.is_instance_of::<PyString>() {
+                return Err(PyTypeError::new_err("dict keys must be str"));
+            }
+            map.insert(key.extract()?, to_value(&value)?);
+        }
+        return Ok(Value::Object(map));
+    }
+
+    Err(PyTypeError::new_err(format!(
+        "unsupported type {}",
+        object.get_type().name()?
+    )))
+}
+
+/// Convert JSON to native Python values.
+pub fn to_python(py: Python<'_>, value: &Value) -> PyResult<Py<PyAny>> {
+    match *value {
+        Value::Null => Ok(py.None()),
+        Value::Bool(b) => Ok(PyBool::new(py, b).to_owned().into_any().unbind()),
+        Value::Number(ref number) => {
+            if let Some(n) = number.as_i64() {
+                Ok(n.into_pyobject(py)?.into_any().unbind())
+            } else if let Some(n) = number.as_u64() {
+                Ok(n.into_pyobject(py)?.into_any().unbind())
+            } else {
+                let n = number.as_f64().unwrap_or(f64::NAN);
+                Ok(n.into_pyobject(py)?.into_any().unbind())
+            }
+        }
+        Value::String(ref s) => Ok(PyString::new(py, s).into_any().unbind()),
+        Value::Array(ref array) => {
+            let list = PyList::empty(py);
+            for item in array {
+                list.append(to_python(py, item)?)?;
+            }
+            Ok(list.into_any().unbind())
+        }
+        Value::Object(ref map) => {
+            let dict = PyDict::new(py);
+            for (key, item) in map {
+                dict.set_item(key, to_python(py, item)?)?;
+            }
+            Ok(dict.into_any().unbind())
+        }
+    }
+}
+
+#[pyclass(name = "Packer")]
+pub struct PyPacker {
+    packer: Packer,
+    options: PackOptions,
+}
+
+#[pymethods]
+impl PyPacker {
+    #[new]
+    pub fn new() -> PyPacker {
+        PyPacker {
+            packer: Packer::new(),
+            options: PackOptions::new(),
+        }
+    }
+
+    pub fn pack(&mut self, py: Python<'_>, value: &Bound<'_, PyAny>) -> PyResult<Py<PyAny>> {
+        match self.packer.pack(&to_value(value)?, &self.options) {
+            Ok(packed) => to_python(py, &packed),
+            Err(err) => Err(PyValueError::new_err(err.to_string())),
+        }
+    }
+
+    pub fn pack_string(&mut self, py: Python<'_>, string: &str) -> PyResult<Py<PyAny>> {
+        match self.packer.pack_string(string, &self.options) {
+            Ok(packed) => to_python(py, &packed),
+            Err(err) => Err(PyValueError::new_err(err.to_string())),
+        }
+    }
+
+    pub fn set_max_dict_size(&mut self, value: u64) {
+        self.packer.set_max_dict_size(value);
+    }
+
+    pub fn reset(&mut self) {
+        self.packer.reset();
+    }
+}
+
+impl Default for PyPacker {
+    fn default() -> PyPacker {
+        PyPacker::new()
+    }
+}
+
+#[pyclass(name = "Unpacker")]
+pub struct PyUnpacker {
+    unpacker: Unpacker,
+}
+
+#[pymethods]
+impl PyUnpacker {
+    #[new]
+    pub fn new() -> PyUnpacker {
+        PyUnpacker {
+            unpacker: Unpacker::new(),
+        }
+    }
+
+    pub fn unpack(&mut self, py: Python<'_>, packed: &Bound<'_, PyAny>) -> PyResult<Py<PyAny>> {
+        match self.unpacker.unpack::<Value>(&to_value(packed)?) {
+            Ok(unpacked) => to_python(py, &unpacked),
+            Err(err) => Err(PyValueError::new_err(err.cause)),
+        }
+    }
+
+    pub fn unpack_string(&mut self, packed: &Bound<'_, PyAny>) -> PyResult<String> {
+        match self.unpacker.unpack_string(&to_value(packed)?) {
+            Ok(unpacked) => Ok(unpacked),
+            Err(err) => Err(PyValueError::new_err(err.cause)),
+        }
+    }
+
+    pub fn set_max_dict_size(&mut self, value: u64) {
+        self.unpacker.set_max_dict_size(value);
+    }
+
+    /// Forget the dictionary, e.g. before decoding a capture from its first frame again.
+    pub fn reset(&mut self) {
+        self.unpacker.reset();
+    }
+}
+
+impl Default for PyUnpacker {
+    fn default() -> PyUnpacker {
+        PyUnpacker::new()
+    }
+}
+
+#[pymodule]
+fn jsonm(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyPacker>()?;
+    module.add_class::<PyUnpacker>()?;
+    Ok(())
+}
@@ -0,0 +1,38 @@
+/// Describes a struct whose field names are known statically.
+///
+/// Objects matching the schema are packed positionally: only the schema id and the
+/// field values are sent, keys are neither emitted nor memoised.
+pub trait JsonmSchema {
+    /// Id identifying the schema on both sides. Must be unique per session.
+    const SCHEMA_ID: u64;
+    /// Field names in serialization order.
+    const FIELDS: &'static [&'static str];
+}
+
+/// Runtime schema definition registered on both the packer and the unpacker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schema {
+    pub id: u64,
+    pub fields: Vec<String>,
+}
+
+impl Schema {
+    pub fn new(id: u64, fields: &[&str]) -> Schema {
+        Schema {
+            id,
+            fields: fields.iter().map(|f| (*f).to_owned()).collect(),
+        }
+    }
+
+    /// Build the schema of a type implementing `JsonmSchema`.
+    pub fn of<T: JsonmSchema>() -> Schema {
+        Schema::new(T::SCHEMA_ID, T::FIELDS)
+    }
+
+    pub(crate) fn matches<'a, I>(&self, keys: I) -> bool
+    where
+        I: ExactSizeIterator<Item = &'a String>,
+    {
+        keys.len() == self.fields.len() && keys.zip(&self.fields).all(|(k, f)| k == f)
+    }
+}
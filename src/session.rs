@@ -0,0 +1,188 @@
+use frame::{TYPE_ARRAY, TYPE_HANDSHAKE};
+use serde_json::{Map, Value};
+use unpacker::{UnpackerError, UnpackerErrorKind};
+
+/// Decides which values are worth a dictionary slot. Values that are not memoised are
+/// sent as is every time, without taking a slot a longer value could use.
+/// Keys are always memoised.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoPolicy {
+    /// Strings and numbers shorter than this are sent as is. Strings are measured
+    /// unescaped in UTF-8 bytes, numbers as their JSON text. A reference such as `1534`
+    /// takes 4 bytes. Default - 0
+    pub min_len: usize,
+    /// Strings and numbers longer than this, measured like `min_len`, are sent as is.
+    /// Default - None
+    pub max_len: Option<usize>,
+    /// Memoise `true`, `false` and `null`. Default - true
+    pub memoise_constants: bool,
+    /// Objects packed to at most this many references, keys and values, are memoised as a
+    /// whole and sent as a single reference when repeated. Default - 12
+    pub max_complex_object_size: usize,
+    /// Also memoise objects as a whole when some values are arrays of references, e.g.
+    /// coordinates. Their elements count towards `max_complex_object_size`. Default - false
+    pub memoise_nested_arrays: bool,
+    /// Memoise repeated arrays as a whole under the same rules as objects, e.g. tag lists.
+    /// Default - false
+    pub memoise_arrays: bool,
+}
+
+impl MemoPolicy {
+    /// Memoise every value, like other jsonm implementations do.
+    pub fn new() -> MemoPolicy {
+        MemoPolicy {
+            min_len: 0,
+            max_len: None,
+            memoise_constants: true,
+            max_complex_object_size: 12,
+            memoise_nested_arrays: false,
+            memoise_arrays: false,
+        }
+    }
+
+    /// Whether a string value is memoised.
+    pub(crate) fn memoises_string(&self, string: &str) -> bool {
+        self.fits(string.len())
+    }
+
+    /// Whether a number, boolean or null is memoised, given its JSON text.
+    pub(crate) fn memoises_literal(&self, text: &str) -> bool {
+        match text {
+            "true" | "false" | "null" if !self.memoise_constants => false,
+            _ => self.fits(text.len()),
+        }
+    }
+
+    /// Whether an object or array is memoised as a whole, given its packed keys and
+    /// values or its packed elements.
+    /// Only depends on what is sent, so both sides agree.
+    pub(crate) fn memoises_complex(&self, packed: &[Value]) -> bool {
+        let mut size = packed.len();
+        for entry in packed {
+            match *entry {
+                Value::Number(_) => {}
+                Value::Array(ref arr)
+                    if self.memoise_nested_arrays
+                        && arr.first().and_then(Value::as_i64) == Some(TYPE_ARRAY)
+                        && arr[1..].iter().all(Value::is_number) =>
+                {
+                    size += arr.len() - 1
+                }
+                _ => return false,
+            }
+        }
+        size <= self.max_complex_object_size
+    }
+
+    fn fits(&self, len: usize) -> bool {
+        len >= self.min_len && self.max_len.is_none_or(|max_len| len <= max_len)
+    }
+}
+
+impl Default for MemoPolicy {
+    fn default() -> MemoPolicy {
+        MemoPolicy::new()
+    }
+}
+
+/// Settings the packer and the unpacker of a session must agree on. The packer sends
+/// them as a handshake frame, `[-7, {"max_dict_size": 2000, ...}]`, before the first message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionConfig {
+    pub max_dict_size: u64,
+    pub memo_policy: MemoPolicy,
+}
+
+impl SessionConfig {
+    pub fn new() -> SessionConfig {
+        SessionConfig {
+            max_dict_size: 2000,
+            memo_policy: MemoPolicy::new(),
+        }
+    }
+
+    /// Encode the config as a handshake frame.
+    pub fn to_handshake(&self) -> Value {
+        json!([
+            TYPE_HANDSHAKE,
+            {
+                "max_dict_size": self.max_dict_size,
+                "min_len": self.memo_policy.min_len,
+                "max_len": self.memo_policy.max_len,
+                "memoise_constants": self.memo_policy.memoise_constants,
+                "max_complex_object_size": self.memo_policy.max_complex_object_size,
+                "memoise_nested_arrays": self.memo_policy.memoise_nested_arrays,
+                "memoise_arrays": self.memo_policy.memoise_arrays,
+            }
+        ])
+    }
+
+    /// Decode a handshake frame. Missing settings keep their defaults.
+    pub fn from_handshake(frame: &Value) -> Result<SessionConfig, UnpackerError> {
+        let settings = match frame.as_array().map(|arr| arr.as_slice()) {
+            Some([type_id, Value::Object(settings)]) if type_id == TYPE_HANDSHAKE => settings,
+            _ => return Err(handshake_error("handshake frame expected")),
+        };
+
+        let mut config = SessionConfig::new();
+        if let Some(value) = setting(settings, "max_dict_size", Value::as_u64)? {
+            config.max_dict_size = value;
+        }
+        if let Some(value) = setting(settings, "min_len", Value::as_u64)? {
+            config.memo_policy.min_len = value as usize;
+        }
+        match settings.get("max_len") {
+            None | Some(Value::Null) => {}
+            Some(value) => match value.as_u64() {
+                Some(max_len) => config.memo_policy.max_len = Some(max_len as usize),
+                None => return Err(handshake_error("invalid handshake setting max_len")),
+            },
+        }
+        if let Some(value) = setting(settings, "memoise_constants", Value::as_bool)? {
+            config.memo_policy.memoise_constants = value;
+        }
+        if let Some(value) = setting(settings, "max_complex_object_size", Value::as_u64)? {
+            config.memo_policy.max_complex_object_size = value as usize;
+        }
+        if let Some(value) = setting(settings, "memoise_nested_arrays", Value::as_bool)? {
+            config.memo_policy.memoise_nested_arrays = value;
+        }
+        if let Some(value) = setting(settings, "memoise_arrays", Value::as_bool)? {
+            config.memo_policy.memoise_arrays = value;
+        }
+        Ok(config)
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> SessionConfig {
+        SessionConfig::new()
+    }
+}
+
+fn setting<T, F>(
+    settings: &Map<String, Value>,
+    name: &str,
+    get: F,
+) -> Result<Option<T>, UnpackerError>
+where
+    F: Fn(&Value) -> Option<T>,
+{
+    match settings.get(name) {
+        None => Ok(None),
+        Some(value) => match get(value) {
+            Some(value) => Ok(Some(value)),
+            None => Err(handshake_error(&format!(
+                "invalid handshake setting {}",
+                name
+            ))),
+        },
+    }
+}
+
+fn handshake_error(cause: &str) -> UnpackerError {
+    UnpackerError {
+        kind: UnpackerErrorKind::MalformedFrame,
+        cause: cause.to_owned(),
+    }
+}
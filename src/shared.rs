@@ -0,0 +1,168 @@
+//! Handles to a single packing or unpacking session that can be cloned and shared
+//! between threads. Calls are serialized, each one sees the dictionary left by the
+//! previous one.
+extern crate serde;
+
+use self::serde::{Deserialize, Serialize};
+use packer::{PackOptions, Packer, PackerError};
+use serde_json::Value;
+use stats::PackerStats;
+use std::sync::{Arc, Mutex, MutexGuard};
+use unpacker::{Unpacker, UnpackerError, UnpackerErrorKind};
+
+/// `Packer` shared between threads. Clones pack into the same session.
+#[derive(Debug, Clone, Default)]
+pub struct SharedPacker {
+    packer: Arc<Mutex<Packer>>,
+}
+
+impl SharedPacker {
+    pub fn new() -> SharedPacker {
+        SharedPacker::from(Packer::new())
+    }
+
+    /// Pack an JSON-like object. The sequence id is assigned together with the frame content,
+    /// but frames packed by different threads may be sent out of order, see `pack_then`.
+    pub fn pack<T>(&self, object: &T, options: &PackOptions) -> Result<Value, PackerError>
+    where
+        T: Serialize,
+    {
+        self.lock()?.pack(object, options)
+    }
+
+    /// Pack an JSON-like object and pass the frame to `send` before any other thread can
+    /// pack, so frames reach the connection in sequence order.
+    pub fn pack_then<T, F, R>(
+        &self,
+        object: &T,
+        options: &PackOptions,
+        send: F,
+    ) -> Result<R, PackerError>
+    where
+        T: Serialize,
+        F: FnOnce(Value) -> R,
+    {
+        let mut packer = self.lock()?;
+        let packed = packer.pack(object, options)?;
+        Ok(send(packed))
+    }
+
+    /// Pack a string, see `Packer::pack_string`.
+    pub fn pack_string(
+        &self,
+        string_to_pack: &str,
+        options: &PackOptions,
+    ) -> Result<Value, PackerError> {
+        self.lock()?.pack_string(string_to_pack, options)
+    }
+
+    /// Pack a diff against the previous message, see `Packer::pack_delta`.
+    pub fn pack_delta<T>(&self, object: &T, options: &PackOptions) -> Result<Value, PackerError>
+    where
+        T: Serialize,
+    {
+        self.lock()?.pack_delta(object, options)
+    }
+
+    /// Run `f` with exclusive access to the packer, e.g. to pack and send a string frame.
+    pub fn with<F, R>(&self, f: F) -> Result<R, PackerError>
+    where
+        F: FnOnce(&mut Packer) -> R,
+    {
+        Ok(f(&mut *self.lock()?))
+    }
+
+    pub fn stats(&self) -> PackerStats {
+        match self.packer.lock() {
+            Ok(packer) => packer.stats(),
+            Err(poisoned) => poisoned.into_inner().stats(),
+        }
+    }
+
+    /// Reset the session. Also recovers a packer left inconsistent by a panic.
+    pub fn reset(&self) {
+        match self.packer.lock() {
+            Ok(mut packer) => packer.reset(),
+            Err(poisoned) => {
+                poisoned.into_inner().reset();
+                self.packer.clear_poison();
+            }
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Packer>, PackerError> {
+        match self.packer.lock() {
+            Ok(packer) => Ok(packer),
+            Err(_err) => Err(PackerError::new(
+                "packer poisoned by a panic, reset it first",
+            )),
+        }
+    }
+}
+
+impl From<Packer> for SharedPacker {
+    fn from(packer: Packer) -> SharedPacker {
+        SharedPacker {
+            packer: Arc::new(Mutex::new(packer)),
+        }
+    }
+}
+
+/// `Unpacker` shared between threads. Clones unpack from the same session.
+#[derive(Debug, Clone, Default)]
+pub struct SharedUnpacker {
+    unpacker: Arc<Mutex<Unpacker>>,
+}
+
+impl SharedUnpacker {
+    pub fn new() -> SharedUnpacker {
+        SharedUnpacker::from(Unpacker::new())
+    }
+
+    /// Unpack an packed object to its original input.
+    pub fn unpack<T>(&self, packed_object: &Value) -> Result<T, UnpackerError>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        self.lock()?.unpack(packed_object)
+    }
+
+    /// Unpack a diff produced by `Packer::pack_delta`.
+    pub fn unpack_delta<T>(&self, packed_object: &Value) -> Result<T, UnpackerError>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        self.lock()?.unpack_delta(packed_object)
+    }
+
+    /// Unpack an object to a string.
+    pub fn unpack_string(&self, packed_object: &Value) -> Result<String, UnpackerError> {
+        self.lock()?.unpack_string(packed_object)
+    }
+
+    /// Run `f` with exclusive access to the unpacker.
+    pub fn with<F, R>(&self, f: F) -> Result<R, UnpackerError>
+    where
+        F: FnOnce(&mut Unpacker) -> R,
+    {
+        Ok(f(&mut *self.lock()?))
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Unpacker>, UnpackerError> {
+        match self.unpacker.lock() {
+            Ok(unpacker) => Ok(unpacker),
+            Err(_err) => Err(UnpackerError {
+                kind: UnpackerErrorKind::InvalidState,
+                cause: "unpacker poisoned by a panic".to_owned(),
+            }),
+        }
+    }
+}
+
+impl From<Unpacker> for SharedUnpacker {
+    fn from(unpacker: Unpacker) -> SharedUnpacker {
+        SharedUnpacker {
+            unpacker: Arc::new(Mutex::new(unpacker)),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use serde_json::{self, Value};
+use std::fmt;
+use std::io;
+use std::ops::Sub;
+
+/// Statistics collected by a `Packer`, either for a single message or since its creation.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct PackerStats {
+    /// Number of packed messages.
+    pub messages: u64,
+    /// Size of the input serialized as JSON. Only measured when enabled with
+    /// `Packer::set_measure_sizes`.
+    pub raw_bytes: u64,
+    /// Size of the packed output serialized as JSON. Only measured when enabled with
+    /// `Packer::set_measure_sizes`.
+    pub packed_bytes: u64,
+    /// Values and keys replaced by a dictionary reference.
+    pub dict_hits: u64,
+    /// Values and keys added to the dictionary.
+    pub dict_misses: u64,
+    /// Dictionary entries overwritten after the dictionary wrapped around.
+    pub evictions: u64,
+    /// Objects and arrays replaced by a single dictionary reference.
+    pub complex_object_hits: u64,
+    /// Sequence id of the last packed message.
+    pub sequence_id: i64,
+}
+
+impl PackerStats {
+    /// Share of values and keys found in the dictionary, between 0 and 1.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.dict_hits + self.dict_misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.dict_hits as f64 / total as f64
+    }
+
+    /// Packed size relative to the raw size, between 0 and 1 when packing pays off.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.raw_bytes == 0 {
+            return 0.0;
+        }
+        self.packed_bytes as f64 / self.raw_bytes as f64
+    }
+}
+
+impl Sub for PackerStats {
+    type Output = PackerStats;
+
+    fn sub(self, other: PackerStats) -> PackerStats {
+        PackerStats {
+            messages: self.messages - other.messages,
+            raw_bytes: self.raw_bytes - other.raw_bytes,
+            packed_bytes: self.packed_bytes - other.packed_bytes,
+            dict_hits: self.dict_hits - other.dict_hits,
+            dict_misses: self.dict_misses - other.dict_misses,
+            evictions: self.evictions - other.evictions,
+            complex_object_hits: self.complex_object_hits - other.complex_object_hits,
+            sequence_id: self.sequence_id,
+        }
+    }
+}
+
+/// Receives statistics after every packed message, e.g. to export them per connection.
+pub trait Metrics: Send + Sync {
+    /// Called with the statistics of the message just packed and the running totals.
+    fn on_pack(&self, message: &PackerStats, totals: &PackerStats);
+}
+
+pub(crate) struct MetricsSink(pub Box<dyn Metrics>);
+
+impl fmt::Debug for MetricsSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MetricsSink")
+    }
+}
+
+struct ByteCounter(u64);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Size of the value serialized as JSON, without allocating the serialized string.
+pub(crate) fn json_size(value: &Value) -> u64 {
+    let mut counter = ByteCounter(0);
+    match serde_json::to_writer(&mut counter, value) {
+        Ok(()) => counter.0,
+        Err(_err) => 0,
+    }
+}
+
+/// `Metrics` implementation reporting to the `metrics` crate facade.
+///
+/// Counters are prefixed with `jsonm_` and labelled with the given connection name.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone)]
+pub struct MetricsRecorder {
+    connection: String,
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsRecorder {
+    pub fn new(connection: &str) -> MetricsRecorder {
+        MetricsRecorder {
+            connection: connection.to_owned(),
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics for MetricsRecorder {
+    fn on_pack(&self, message: &PackerStats, totals: &PackerStats) {
+        let labels = [("connection", self.connection.clone())];
+        ::metrics::counter!("jsonm_messages_total", &labels).increment(message.messages);
+        ::metrics::counter!("jsonm_raw_bytes_total", &labels).increment(message.raw_bytes);
+        ::metrics::counter!("jsonm_packed_bytes_total", &labels).increment(message.packed_bytes);
+        ::metrics::counter!("jsonm_dict_hits_total", &labels).increment(message.dict_hits);
+        ::metrics::counter!("jsonm_dict_misses_total", &labels).increment(message.dict_misses);
+        ::metrics::counter!("jsonm_evictions_total", &labels).increment(message.evictions);
+        ::metrics::counter!("jsonm_complex_object_hits_total", &labels)
+            .increment(message.complex_object_hits);
+        ::metrics::gauge!("jsonm_sequence_id", &labels).set(totals.sequence_id as f64);
+    }
+}
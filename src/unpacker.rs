@@ -1,33 +1,77 @@
-extern crate regex;
 extern crate serde;
 extern crate serde_json;
 
-use self::regex::Regex;
 use self::serde::Deserialize;
-use serde_json::Value;
-use std::collections::HashMap;
+use dictionary::{DictEntry, EntryKind, Resolution};
+use frame::{
+    DELTA_REMOVE, DELTA_SET, MAX_STRING_SPLITTER_ID, MIN_DICT_INDEX, TYPE_ARRAY, TYPE_COLUMNS,
+    TYPE_DELTA, TYPE_NUMBERS, TYPE_PREFIX, TYPE_REORDERED, TYPE_SCHEMA, TYPE_SPLIT_STRING,
+    TYPE_STRING, TYPE_VALUE,
+};
+use schema::Schema;
+use serde_json::{Map, Number, Value};
+use session::{MemoPolicy, SessionConfig};
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
 use std::fmt;
 use std::vec::Vec;
 
 //const OLD_MESSAGE: i32 = -99;
-const MIN_DICT_INDEX: u64 = 3;
-const TYPE_ARRAY: i64 = 0;
-const TYPE_VALUE: i64 = 1;
-const TYPE_STRING: i64 = 2;
-const MAX_PACK_COMPLEX_OBJECT_SIZE: usize = 12;
 
-#[derive(Default, Debug)]
+/// Dictionary resolutions keyed by the address of the packed node and, for arrays, their
+/// length. A message body shares its address with the frame, but not its length.
+type Trace = HashMap<(usize, Option<usize>), VecDeque<Resolution>>;
+
+/// A reverted change of the unpacker state.
+#[derive(Debug, Clone)]
+enum Undo {
+    /// A dictionary entry was written at the index, replacing the previous entry if any.
+    AddToDict(u64, Option<(Value, EntryKind)>),
+    /// The last unpacked message was replaced.
+    LastValue(Option<Value>),
+}
+
+/// Unpacker state to roll back to when a message fails to unpack.
+struct Savepoint {
+    dict_index: u64,
+    sequence_id: i64,
+}
+
+#[derive(Default, Debug, Clone)]
 pub struct Unpacker {
-    dict: HashMap<u64, String>,
+    dict: HashMap<u64, (Value, EntryKind)>,
     dict_index: u64,
     sequence_id: i64,
     max_dict_size: u64,
-    pending_unpacks: Vec<i32>,
+    max_sequence_len: u64,
+    memo_policy: MemoPolicy,
+    schemas: Vec<Schema>,
+    last_value: Option<Value>,
+    trace: Option<Trace>,
+    /// Undo journal of the message being unpacked.
+    journal: Option<Vec<Undo>>,
+}
+
+/// What an `UnpackerError` is about, independent of the wording of its cause.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnpackerErrorKind {
+    /// The frame is not the next one in sequence.
+    OutOfSequence,
+    /// The frame references a dictionary entry the unpacker doesn't have.
+    UnknownReference,
+    /// The frame references a schema that is not registered.
+    UnknownSchema,
+    /// The frame is not a valid packed message.
+    MalformedFrame,
+    /// The unpacked value does not deserialize into the requested type.
+    WrongType,
+    /// The unpacker can't serve the call, e.g. nothing was unpacked yet.
+    InvalidState,
 }
 
 #[derive(Debug, Clone)]
 pub struct UnpackerError {
+    pub kind: UnpackerErrorKind,
     pub cause: String,
 }
 
@@ -52,12 +96,15 @@ impl Unpacker {
         Unpacker {
             sequence_id: -1,
             max_dict_size: 2000,
+            max_sequence_len: 1_000_000,
             dict_index: MIN_DICT_INDEX,
             ..Default::default()
         }
     }
 
-    /// Unpack an packed object to its original input.
+    /// Unpack an packed object to its original input. On error, including a value that
+    /// does not deserialize into `T`, the unpacker is left unchanged and the frame can be
+    /// unpacked again.
     pub fn unpack<T>(&mut self, packed_object: &Value) -> Result<T, UnpackerError>
     where
         for<'de> T: Deserialize<'de>,
@@ -66,31 +113,238 @@ impl Unpacker {
             return match serde_json::from_value(Value::Null) {
                 Ok(v) => Ok(v),
                 Err(_err) => Err(UnpackerError {
+                    kind: UnpackerErrorKind::WrongType,
                     cause: "wrong end type for Value::Null, use Value type instead".to_owned(),
                 }),
             };
         };
 
+        let savepoint = self.savepoint();
+        let unpacked = match self.unpack_message(packed_object) {
+            Ok(()) => self.deserialize_last_value(),
+            Err(err) => Err(err),
+        };
+        self.finish(savepoint, unpacked)
+    }
+
+    /// Unpack an packed object without deserializing it. The frame is consumed, the
+    /// result can be deserialized into several types with `deserialize_last_value`.
+    pub fn unpack_value(&mut self, packed_object: &Value) -> Result<&Value, UnpackerError> {
+        let savepoint = self.savepoint();
+        let unpacked = if packed_object.is_null() {
+            self.set_last_value(Value::Null);
+            Ok(())
+        } else {
+            self.unpack_message(packed_object)
+        };
+        self.finish(savepoint, unpacked)?;
+        match self.last_value {
+            Some(ref value) => Ok(value),
+            None => Err(UnpackerError {
+                kind: UnpackerErrorKind::InvalidState,
+                cause: "nothing unpacked yet".to_owned(),
+            }),
+        }
+    }
+
+    /// Unpack an packed object, deserializing into a type that may borrow strings
+    /// from the last unpacked value, e.g. `&str` fields. The result lives until the next call.
+    /// This saves the copies of deserializing into owned strings, not those of unpacking:
+    /// dictionary entries are still cloned into the unpacked value on every reference.
+    /// A frame that fails to deserialize is still consumed, as the result borrows from
+    /// the unpacker. Retry with `deserialize_last_value`.
+    pub fn unpack_borrowed<'a, T>(&'a mut self, packed_object: &Value) -> Result<T, UnpackerError>
+    where
+        T: Deserialize<'a>,
+    {
+        if packed_object.is_null() {
+            return match T::deserialize(Value::Null) {
+                Ok(v) => Ok(v),
+                Err(_err) => Err(UnpackerError {
+                    kind: UnpackerErrorKind::WrongType,
+                    cause: "wrong end type for Value::Null, use Value type instead".to_owned(),
+                }),
+            };
+        };
+
+        let savepoint = self.savepoint();
+        let unpacked = self.unpack_message(packed_object);
+        self.finish(savepoint, unpacked)?;
+        self.deserialize_last_value()
+    }
+
+    /// Unpack a diff produced by `Packer::pack_delta`, applying it to the previously
+    /// unpacked message. On error the unpacker is left unchanged.
+    pub fn unpack_delta<T>(&mut self, packed_object: &Value) -> Result<T, UnpackerError>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let savepoint = self.savepoint();
+        let unpacked = match self.unpack_delta_message(packed_object) {
+            Ok(()) => self.deserialize_last_value(),
+            Err(err) => Err(err),
+        };
+        self.finish(savepoint, unpacked)
+    }
+
+    fn unpack_delta_message(&mut self, packed_object: &Value) -> Result<(), UnpackerError> {
         let packed_arr = match packed_object.as_array() {
             Some(packed_arr) => packed_arr,
             None => {
                 return Err(UnpackerError {
+                    kind: UnpackerErrorKind::MalformedFrame,
                     cause: "packed value expected".to_owned(),
                 })
             }
         };
-
-        if !packed_arr[packed_arr.len() - 1].is_number() {
+        if packed_arr.first().and_then(|t| t.as_i64()) != Some(TYPE_DELTA) {
             return Err(UnpackerError {
-                cause: "packed value expected".to_owned(),
+                kind: UnpackerErrorKind::MalformedFrame,
+                cause: "delta expected".to_owned(),
             });
+        }
+
+        let packed_body = self.start_message(packed_arr)?;
+        // Applied to a copy, the previous message is restored when an operation fails.
+        let mut unpacked = match self.last_value {
+            Some(ref v) => v.clone(),
+            None => Value::Null,
+        };
+        for op in &packed_body[1..] {
+            self.apply_delta_op(&mut unpacked, op)?;
+        }
+        self.set_last_value(unpacked);
+        Ok(())
+    }
+
+    /// Unpack an object to a string.
+    pub fn unpack_string(&mut self, packed_object: &Value) -> Result<String, UnpackerError> {
+        match packed_object.as_array() {
+            Some(arr) if arr.is_empty() => Err(UnpackerError {
+                kind: UnpackerErrorKind::MalformedFrame,
+                cause: "packed value expected".to_owned(),
+            }),
+            Some(arr) => {
+                if is_string_frame(arr) {
+                    return self.unpack(packed_object);
+                }
+
+                match self.unpack::<Value>(packed_object) {
+                    Ok(s) => Ok(s.to_string()),
+                    Err(err) => Err(err),
+                }
+            }
+            None => match self.unpack::<Value>(packed_object) {
+                Ok(s) => Ok(s.to_string()),
+                Err(err) => Err(err),
+            },
+        }
+    }
+
+    /// Dictionary entries ordered by index.
+    pub fn dictionary(&self) -> Vec<DictEntry> {
+        let mut entries: Vec<DictEntry> = self
+            .dict
+            .iter()
+            .map(|(index, &(ref value, kind))| DictEntry {
+                index: *index,
+                value: value.clone(),
+                kind,
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.index);
+        entries
+    }
+
+    /// Annotate a packed frame with the dictionary entries it references or adds, e.g.
+    /// `{"$ref": 3, "kind": "key", "value": "foo"}`. Works on a copy of the unpacker,
+    /// so the frame must be the next one in sequence and is not consumed.
+    pub fn explain(&self, packed_object: &Value) -> Result<Value, UnpackerError> {
+        match self.clone().unpack_explained(packed_object)? {
+            (Value::Array(mut body), Some(sequence_id)) => {
+                body.push(sequence_id);
+                Ok(Value::Array(body))
+            }
+            // A memoised message body is annotated as a whole.
+            (body, Some(sequence_id)) => Ok(json!([body, sequence_id])),
+            (body, None) => Ok(body),
+        }
+    }
+
+    /// Unpack a frame, returning its body annotated like `explain` does and its sequence id.
+    pub(crate) fn unpack_explained(
+        &mut self,
+        packed_object: &Value,
+    ) -> Result<(Value, Option<Value>), UnpackerError> {
+        self.trace = Some(Trace::new());
+        let type_id = packed_object
+            .as_array()
+            .and_then(|arr| arr.first())
+            .and_then(|t| t.as_i64());
+        let unpacked = if type_id == Some(TYPE_DELTA) {
+            self.unpack_delta::<Value>(packed_object)
+        } else {
+            self.unpack::<Value>(packed_object)
+        };
+
+        let mut trace = self.trace.take().unwrap_or_default();
+        unpacked?;
+        match packed_object.as_array().and_then(|arr| arr.split_last()) {
+            Some((sequence_id, body)) => {
+                Ok((annotate_array(body, &mut trace), Some(sequence_id.clone())))
+            }
+            None => Ok((annotate(packed_object, &mut trace), None)),
+        }
+    }
+
+    fn unpack_message(&mut self, packed_object: &Value) -> Result<(), UnpackerError> {
+        let packed_arr = match packed_object.as_array() {
+            Some(packed_arr) => packed_arr,
+            None => {
+                return Err(UnpackerError {
+                    kind: UnpackerErrorKind::MalformedFrame,
+                    cause: "packed value expected".to_owned(),
+                })
+            }
+        };
+
+        let packed_body = self.start_message(packed_arr)?;
+        let unpacked = self.unpack_array(packed_body)?;
+        self.set_last_value(unpacked);
+        Ok(())
+    }
+
+    /// Deserialize the last unpacked message again, e.g. into another type after
+    /// `unpack_value`.
+    pub fn deserialize_last_value<'a, T>(&'a self) -> Result<T, UnpackerError>
+    where
+        T: Deserialize<'a>,
+    {
+        let value = match self.last_value {
+            Some(ref value) => value,
+            None => {
+                return Err(UnpackerError {
+                    kind: UnpackerErrorKind::InvalidState,
+                    cause: "nothing unpacked yet".to_owned(),
+                })
+            }
         };
 
-        let value = &packed_arr[packed_arr.len() - 1];
-        let remote_sequence_id = match value.as_i64() {
+        match T::deserialize(value) {
+            Ok(result) => Ok(result),
+            Err(_err) => Err(UnpackerError {
+                kind: UnpackerErrorKind::WrongType,
+                cause: "unable to unpack to specific type".to_owned(),
+            }),
+        }
+    }
+
+    fn start_message<'a>(&mut self, packed_arr: &'a [Value]) -> Result<&'a [Value], UnpackerError> {
+        let remote_sequence_id = match packed_arr.last().and_then(|v| v.as_i64()) {
             Some(v) => v,
             None => {
                 return Err(UnpackerError {
+                    kind: UnpackerErrorKind::MalformedFrame,
                     cause: "packed value expected".to_owned(),
                 })
             }
@@ -100,44 +354,112 @@ impl Unpacker {
             self.dict_index = MIN_DICT_INDEX;
         } else if remote_sequence_id != (self.sequence_id + 1) {
             return Err(UnpackerError {
+                kind: UnpackerErrorKind::OutOfSequence,
                 cause: "message unpacked out of sequence or already unpacked".to_owned(),
             });
         };
 
         self.sequence_id = remote_sequence_id;
-        let unpacked = match self.unpack_object(&json!(packed_arr[..(packed_arr.len() - 1)])) {
-            Ok(result) => result,
-            Err(err) => return Err(err),
-        };
+        Ok(&packed_arr[..(packed_arr.len() - 1)])
+    }
 
-        let result: T = match serde_json::from_value(unpacked) {
-            Ok(result) => result,
-            Err(_err) => {
+    fn apply_delta_op(&mut self, target: &mut Value, op: &Value) -> Result<(), UnpackerError> {
+        let op = match op.as_array() {
+            Some(op) if !op.is_empty() => op,
+            _ => {
                 return Err(UnpackerError {
-                    cause: "unable to unpack to specific type".to_owned(),
+                    kind: UnpackerErrorKind::MalformedFrame,
+                    cause: "delta operation expected".to_owned(),
+                })
+            }
+        };
+        let (segments, value) = match op[0].as_u64() {
+            Some(DELTA_SET) if op.len() >= 2 => (&op[1..op.len() - 1], Some(&op[op.len() - 1])),
+            Some(DELTA_REMOVE) if op.len() >= 2 => (&op[1..], None),
+            _ => {
+                return Err(UnpackerError {
+                    kind: UnpackerErrorKind::MalformedFrame,
+                    cause: "unknown delta operation".to_owned(),
                 })
             }
         };
-        Ok(result)
-    }
 
-    /// Unpack an object to a string.
-    pub fn unpack_string(&mut self, packed_object: &Value) -> Result<String, UnpackerError> {
-        match packed_object.as_array() {
-            Some(arr) => {
-                if arr[0] == TYPE_STRING {
-                    return self.unpack(packed_object);
+        let mut path: Vec<Value> = Vec::new();
+        for segment in segments {
+            path.push(self.unpack_path_segment(segment)?);
+        }
+        let value = match value {
+            Some(v) => Some(self.unpack_object_value(v)?),
+            None => None,
+        };
+
+        let (last, parents) = match path.split_last() {
+            Some(split) => split,
+            None => {
+                *target = value.unwrap_or(Value::Null);
+                return Ok(());
+            }
+        };
+
+        let mut parent = target;
+        for segment in parents {
+            let child = match segment {
+                Value::String(key) => parent.get_mut(key.as_str()),
+                _ => segment
+                    .as_u64()
+                    .and_then(move |i| parent.get_mut(i as usize)),
+            };
+            parent = match child {
+                Some(child) => child,
+                None => {
+                    return Err(UnpackerError {
+                        kind: UnpackerErrorKind::MalformedFrame,
+                        cause: "delta path not found".to_owned(),
+                    })
                 }
+            };
+        }
 
-                match self.unpack::<Value>(packed_object) {
-                    Ok(s) => Ok(s.to_string()),
-                    Err(err) => Err(err),
+        match (parent, last, value) {
+            (Value::Object(obj), Value::String(key), Some(value)) => {
+                obj.insert(key.clone(), value);
+            }
+            (Value::Object(obj), Value::String(key), None) => {
+                obj.shift_remove(key);
+            }
+            (Value::Array(arr), Value::Number(i), Some(value)) => {
+                match i.as_u64().and_then(|i| arr.get_mut(i as usize)) {
+                    Some(element) => *element = value,
+                    None => {
+                        return Err(UnpackerError {
+                            kind: UnpackerErrorKind::MalformedFrame,
+                            cause: "delta path not found".to_owned(),
+                        })
+                    }
                 }
             }
-            None => match self.unpack::<Value>(packed_object) {
-                Ok(s) => Ok(s.to_string()),
-                Err(err) => Err(err),
-            },
+            _ => {
+                return Err(UnpackerError {
+                    kind: UnpackerErrorKind::MalformedFrame,
+                    cause: "delta path not found".to_owned(),
+                })
+            }
+        }
+        Ok(())
+    }
+
+    /// Keys are packed as regular values, array indexes as negative numbers.
+    fn unpack_path_segment(&mut self, segment: &Value) -> Result<Value, UnpackerError> {
+        if let Some(i) = segment.as_i64() {
+            if i < 0 {
+                return Ok(json!(-i - 1));
+            }
+        }
+
+        let key = self.unpack_scalar(segment, EntryKind::Key)?;
+        match key.as_str() {
+            Some(_) => Ok(key),
+            None => Ok(json!(key.to_string())),
         }
     }
 
@@ -146,86 +468,88 @@ impl Unpacker {
             return Ok(Value::Null);
         };
 
-        if !packed_object.is_array() {
-            return self.unpack_value(packed_object);
+        match packed_object.as_array() {
+            Some(packed_array) => self.unpack_array(packed_array),
+            None => self.unpack_scalar(packed_object, EntryKind::Value),
         }
+    }
 
-        let packed_array = match packed_object.as_array() {
-            Some(packed_array) => packed_array,
-            None => {
-                return Err(UnpackerError {
-                    cause: "wrong packed object".to_owned(),
-                })
-            }
-        };
-
-        let type_value = &packed_array[0];
-        let type_id = match type_value.as_i64() {
-            Some(i) => i,
-            None => -1,
-        };
+    fn unpack_array(&mut self, packed_array: &[Value]) -> Result<Value, UnpackerError> {
+        let type_id = packed_array.first().and_then(|t| t.as_i64());
 
-        if type_id == TYPE_ARRAY {
-            return packed_array[1..]
+        if type_id == Some(TYPE_ARRAY) {
+            let unpacked = packed_array[1..]
                 .iter()
-                .map(|v| self.unpack_object(&v))
-                .collect();
+                .map(|v| self.unpack_object(v))
+                .collect::<Result<Value, UnpackerError>>()?;
+            // Mirrors `Packer::try_pack_complex_array`.
+            let elements = &packed_array[1..];
+            if self.memo_policy.memoise_arrays && self.memo_policy.memoises_complex(elements) {
+                let index = self.add_to_dict(unpacked.clone(), EntryKind::ComplexObject);
+                self.trace_array_resolution(packed_array, true, index);
+            }
+            return Ok(unpacked);
         }
-        if type_id == TYPE_STRING {
-            return match packed_array[1..]
-                .iter()
-                .map(|v| self.unpack_object(&v))
-                .collect::<Result<Value, _>>()
-            {
-                Ok(arr) => {
-                    let vec = match arr.as_array() {
-                        Some(a) => a,
-                        None => {
-                            return Err(UnpackerError {
-                                cause: "expected array, got something else".to_owned(),
-                            })
-                        }
-                    };
-                    Ok(json!(vec.iter().fold("".to_owned(), |acc, x| {
-                        if acc.is_empty() {
-                            x.as_str().unwrap().to_owned()
-                        } else {
-                            acc + "\n" + x.as_str().unwrap()
-                        }
-                    })))
+        if type_id == Some(TYPE_STRING) {
+            let mut lines: Vec<String> = Vec::new();
+            for packed_line in &packed_array[1..] {
+                match self.unpack_object(packed_line)? {
+                    Value::String(line) => lines.push(line),
+                    _ => {
+                        return Err(UnpackerError {
+                            kind: UnpackerErrorKind::MalformedFrame,
+                            cause: "expected string line, got something else".to_owned(),
+                        })
+                    }
                 }
-                Err(err) => return Err(err),
+            }
+            return Ok(json!(lines.join("\n")));
+        }
+        if type_id == Some(TYPE_VALUE) {
+            return match packed_array.get(1) {
+                Some(packed_value) => self.unpack_object(packed_value),
+                None => Err(UnpackerError {
+                    kind: UnpackerErrorKind::MalformedFrame,
+                    cause: "packed value expected".to_owned(),
+                }),
             };
         }
-        if type_id == TYPE_VALUE {
-            return self.unpack_value(&packed_array[1]);
+        if type_id == Some(TYPE_SCHEMA) {
+            return self.unpack_schema_object(&packed_array[1..]);
+        }
+        if type_id == Some(TYPE_COLUMNS) {
+            return self.unpack_columns(&packed_array[1..]);
+        }
+        if type_id == Some(TYPE_NUMBERS) {
+            return unpack_numeric_sequence(&packed_array[1..], self.max_sequence_len);
+        }
+        if type_id == Some(TYPE_SPLIT_STRING) {
+            return self.unpack_split_string(&packed_array[1..]);
+        }
+        if type_id == Some(TYPE_PREFIX) {
+            return self.unpack_prefixed_string(&packed_array[1..]);
+        }
+        if type_id == Some(TYPE_REORDERED) {
+            return self.unpack_reordered_object(packed_array);
+        }
+        if type_id == Some(TYPE_DELTA) {
+            return Err(UnpackerError {
+                kind: UnpackerErrorKind::MalformedFrame,
+                cause: "unexpected delta, use unpack_delta instead".to_owned(),
+            });
         }
 
-        let mut contains_unmemoised = false;
         let mut processed_object: Vec<Value> = Vec::new();
-        for item in packed_array {
-            if item.is_object() || item.is_array() {
-                let value = match self.unpack_object(&item) {
-                    Ok(v) => v,
-                    Err(err) => return Err(err),
-                };
-                contains_unmemoised = true;
-                processed_object.push(value);
+        let key_count = packed_array.len() / 2;
+        for (i, item) in packed_array.iter().enumerate() {
+            if i < key_count {
+                processed_object.push(self.unpack_scalar(item, EntryKind::Key)?);
             } else {
-                if !item.is_number() {
-                    contains_unmemoised = true;
-                }
-                let value = match self.unpack_value(&item) {
-                    Ok(v) => v,
-                    Err(err) => return Err(err),
-                };
-
-                processed_object.push(value);
+                processed_object.push(self.unpack_object_value(item)?);
             }
         }
 
-        let mut result: HashMap<String, Value> = HashMap::new();
-        let key_count = processed_object.len() / 2;
+        let mut result = Map::new();
         for i in 0..key_count {
             let key_value = &processed_object[i];
             let key = match processed_object[i].as_str() {
@@ -235,93 +559,362 @@ impl Unpacker {
             result.insert(key, processed_object[i + key_count].clone());
         }
 
-        let json_result = json!(result);
-        if !contains_unmemoised && packed_array.len() <= MAX_PACK_COMPLEX_OBJECT_SIZE {
-            self.add_to_dict(&json_result.to_string());
+        let json_result = Value::Object(result);
+        if self.memo_policy.memoises_complex(packed_array) {
+            let index = self.add_to_dict(json_result.clone(), EntryKind::ComplexObject);
+            self.trace_array_resolution(packed_array, true, index);
         }
 
         Ok(json_result)
     }
 
-    fn unpack_value(&mut self, packed_object: &Value) -> Result<Value, UnpackerError> {
+    /// Unpacks a memoised object sent with its keys in another order,
+    /// `[TYPE_REORDERED, index, positions...]`.
+    fn unpack_reordered_object(&mut self, packed_array: &[Value]) -> Result<Value, UnpackerError> {
+        let index = match packed_array.get(1).and_then(|index| index.as_u64()) {
+            Some(index) => index,
+            None => {
+                return Err(UnpackerError {
+                    kind: UnpackerErrorKind::MalformedFrame,
+                    cause: "packed value expected".to_owned(),
+                })
+            }
+        };
+        let memoised = match self.dict.get(&index) {
+            Some(&(Value::Object(ref obj), _)) => obj,
+            _ => {
+                return Err(UnpackerError {
+                    kind: UnpackerErrorKind::UnknownReference,
+                    cause: "no stored value".to_owned(),
+                })
+            }
+        };
+
+        let entries: Vec<(&String, &Value)> = memoised.iter().collect();
+        let mut result = Map::new();
+        for position in &packed_array[2..] {
+            match position.as_u64().and_then(|p| entries.get(p as usize)) {
+                Some(&(key, value)) => {
+                    result.insert(key.clone(), value.clone());
+                }
+                None => {
+                    return Err(UnpackerError {
+                        kind: UnpackerErrorKind::MalformedFrame,
+                        cause: "invalid key positions".to_owned(),
+                    })
+                }
+            }
+        }
+        if result.len() != entries.len() || packed_array.len() - 2 != entries.len() {
+            return Err(UnpackerError {
+                kind: UnpackerErrorKind::MalformedFrame,
+                cause: "invalid key positions".to_owned(),
+            });
+        }
+
+        self.trace_array_resolution(packed_array, false, index);
+        Ok(Value::Object(result))
+    }
+
+    fn unpack_schema_object(&mut self, packed_values: &[Value]) -> Result<Value, UnpackerError> {
+        let schema_id = match packed_values.first().and_then(|id| id.as_u64()) {
+            Some(id) => id,
+            None => {
+                return Err(UnpackerError {
+                    kind: UnpackerErrorKind::MalformedFrame,
+                    cause: "schema id expected".to_owned(),
+                })
+            }
+        };
+        let fields = match self.schemas.iter().find(|s| s.id == schema_id) {
+            Some(schema) => schema.fields.clone(),
+            None => {
+                return Err(UnpackerError {
+                    kind: UnpackerErrorKind::UnknownSchema,
+                    cause: format!("unknown schema {}", schema_id),
+                })
+            }
+        };
+        if fields.len() != packed_values.len() - 1 {
+            return Err(UnpackerError {
+                kind: UnpackerErrorKind::MalformedFrame,
+                cause: format!("wrong number of values for schema {}", schema_id),
+            });
+        }
+
+        let mut result = Map::new();
+        for (field, item) in fields.into_iter().zip(&packed_values[1..]) {
+            let value = self.unpack_object_value(item)?;
+            result.insert(field, value);
+        }
+        Ok(Value::Object(result))
+    }
+
+    fn unpack_columns(&mut self, packed_columns: &[Value]) -> Result<Value, UnpackerError> {
+        let packed_keys = match packed_columns.first().and_then(|keys| keys.as_array()) {
+            Some(keys) => keys,
+            None => {
+                return Err(UnpackerError {
+                    kind: UnpackerErrorKind::MalformedFrame,
+                    cause: "column keys expected".to_owned(),
+                })
+            }
+        };
+        if packed_keys.len() != packed_columns.len() - 1 {
+            return Err(UnpackerError {
+                kind: UnpackerErrorKind::MalformedFrame,
+                cause: "wrong number of columns".to_owned(),
+            });
+        }
+
+        let mut keys: Vec<String> = Vec::new();
+        for packed_key in packed_keys {
+            let key = self.unpack_scalar(packed_key, EntryKind::Key)?;
+            keys.push(match key.as_str() {
+                Some(s) => s.to_owned(),
+                None => key.to_string(),
+            });
+        }
+
+        let mut rows: Vec<Map<String, Value>> = Vec::new();
+        for (key, packed_column) in keys.into_iter().zip(&packed_columns[1..]) {
+            let column = match packed_column.as_array() {
+                Some(column) => column,
+                None => {
+                    return Err(UnpackerError {
+                        kind: UnpackerErrorKind::MalformedFrame,
+                        cause: "column values expected".to_owned(),
+                    })
+                }
+            };
+            if rows.is_empty() {
+                rows.resize(column.len(), Map::new());
+            } else if rows.len() != column.len() {
+                return Err(UnpackerError {
+                    kind: UnpackerErrorKind::MalformedFrame,
+                    cause: "columns have different lengths".to_owned(),
+                });
+            }
+
+            for (row, item) in rows.iter_mut().zip(column) {
+                let value = self.unpack_object_value(item)?;
+                row.insert(key.clone(), value);
+            }
+        }
+
+        Ok(Value::Array(rows.into_iter().map(Value::Object).collect()))
+    }
+
+    fn unpack_split_string(&mut self, packed_segments: &[Value]) -> Result<Value, UnpackerError> {
+        match packed_segments.first().and_then(|id| id.as_u64()) {
+            Some(id) if id <= MAX_STRING_SPLITTER_ID => {}
+            _ => {
+                return Err(UnpackerError {
+                    kind: UnpackerErrorKind::MalformedFrame,
+                    cause: "unknown string splitter".to_owned(),
+                })
+            }
+        }
+
+        let mut result = String::new();
+        for packed_segment in &packed_segments[1..] {
+            match self.unpack_object(packed_segment)? {
+                Value::String(segment) => result.push_str(&segment),
+                _ => {
+                    return Err(UnpackerError {
+                        kind: UnpackerErrorKind::MalformedFrame,
+                        cause: "expected string segment, got something else".to_owned(),
+                    })
+                }
+            }
+        }
+        Ok(json!(result))
+    }
+
+    fn unpack_prefixed_string(&mut self, packed_parts: &[Value]) -> Result<Value, UnpackerError> {
+        if packed_parts.len() != 2 {
+            return Err(UnpackerError {
+                kind: UnpackerErrorKind::MalformedFrame,
+                cause: "head and tail expected".to_owned(),
+            });
+        }
+
+        let head = self.unpack_scalar(&packed_parts[0], EntryKind::Value)?;
+        let tail = self.unpack_scalar(&packed_parts[1], EntryKind::Value)?;
+        match (head.as_str(), tail.as_str()) {
+            (Some(head), Some(tail)) => Ok(json!(head.to_owned() + tail)),
+            _ => Err(UnpackerError {
+                kind: UnpackerErrorKind::MalformedFrame,
+                cause: "head and tail expected".to_owned(),
+            }),
+        }
+    }
+
+    fn unpack_object_value(&mut self, item: &Value) -> Result<Value, UnpackerError> {
+        if item.is_object() || item.is_array() {
+            self.unpack_object(item)
+        } else {
+            self.unpack_scalar(item, EntryKind::Value)
+        }
+    }
+
+    fn unpack_scalar(
+        &mut self,
+        packed_object: &Value,
+        kind: EntryKind,
+    ) -> Result<Value, UnpackerError> {
+        let address = packed_object as *const Value as usize;
         if packed_object.is_number() {
             return match packed_object.as_i64() {
                 Some(v) => {
                     if v < 0 {
-                        return Ok(json!(v * -1));
+                        return Ok(json!(-v));
                     }
                     let index = packed_object.as_u64().unwrap();
-                    let string = match self.dict.get(&index) {
-                        Some(s) => s,
+                    let value = match self.dict.get(&index) {
+                        Some((value, _)) => value.clone(),
                         None => {
                             return Err(UnpackerError {
+                                kind: UnpackerErrorKind::UnknownReference,
                                 cause: "no stored value".to_owned(),
                             })
                         }
                     };
-                    let json: serde_json::Value = match serde_json::from_str(&string) {
-                        Ok(parsed) => parsed,
-                        Err(_err) => json!(string),
-                    };
-
-                    Ok(json)
+                    self.trace_resolution((address, None), false, index);
+                    Ok(value)
                 }
                 None => Err(UnpackerError {
+                    kind: UnpackerErrorKind::MalformedFrame,
                     cause: "unknown".to_owned(),
                 }),
             };
         };
 
-        if packed_object.is_string() {
-            let string = match packed_object.as_str() {
-                Some(s) => s,
-                None => {
-                    return Err(UnpackerError {
-                        cause: "unknown".to_owned(),
-                    })
-                }
-            };
-
-            let re = Regex::new(r"^-?[0-9]+\.").unwrap();
-            if re.is_match(string) {
-                let _p: Value = match string.parse::<f64>() {
-                    Ok(parse_number) => {
-                        self.add_to_dict(string);
-                        return Ok(json!(parse_number));
+        let (value, memoised) = match *packed_object {
+            Value::String(ref string) => {
+                match looks_like_number(string).then(|| parse_number(string)) {
+                    Some(Some(number)) => {
+                        (Value::Number(number), self.memoises(string, false, kind))
                     }
-                    Err(_err) => Value::Null,
-                };
-            };
-
-            let re = Regex::new(r"^-?[0-9\.]").unwrap();
-            if re.is_match(string) {
-                let _p: Value = match string.parse::<i64>() {
-                    Ok(parse_number) => {
-                        self.add_to_dict(string);
-                        return Ok(json!(parse_number));
+                    _ => {
+                        let unescaped = string.strip_prefix('~').unwrap_or(string);
+                        (
+                            Value::String(unescaped.to_owned()),
+                            self.memoises(unescaped, true, kind),
+                        )
                     }
-                    Err(_err) => Value::Null,
-                };
-            };
-
-            let value = if !string.is_empty() && &string[0..1] == "~" {
-                &string[1..]
-            } else {
-                string
-            };
+                }
+            }
+            // The packer memoises booleans and nulls too, keep the dictionary in sync.
+            _ => (
+                packed_object.clone(),
+                self.memoises(&packed_object.to_string(), false, kind),
+            ),
+        };
+        if !memoised {
+            return Ok(value);
+        }
+        let index = self.add_to_dict(value.clone(), kind);
+        self.trace_resolution((address, None), true, index);
+        Ok(value)
+    }
 
-            self.add_to_dict(&json!(value).to_string());
-            return Ok(json!(value));
+    /// Mirrors `Packer::memoises`.
+    fn memoises(&self, text: &str, is_string: bool, kind: EntryKind) -> bool {
+        match kind {
+            EntryKind::Value if is_string => self.memo_policy.memoises_string(text),
+            EntryKind::Value => self.memo_policy.memoises_literal(text),
+            _ => true,
         }
-        Ok(json!(packed_object))
     }
 
-    fn add_to_dict(&mut self, str_value: &str) {
-        self.dict.insert(self.dict_index, str_value.to_owned());
+    fn add_to_dict(&mut self, value: Value, kind: EntryKind) -> u64 {
+        let index = self.dict_index;
+        let replaced = self.dict.insert(index, (value, kind));
+        if let Some(ref mut journal) = self.journal {
+            journal.push(Undo::AddToDict(index, replaced));
+        }
         self.dict_index += 1;
         if self.dict_index >= (self.max_dict_size + MIN_DICT_INDEX) {
             self.dict_index = MIN_DICT_INDEX;
         }
+        index
+    }
+
+    fn set_last_value(&mut self, value: Value) {
+        let previous = self.last_value.replace(value);
+        if let Some(ref mut journal) = self.journal {
+            journal.push(Undo::LastValue(previous));
+        }
+    }
+
+    fn savepoint(&mut self) -> Savepoint {
+        self.journal = Some(Vec::new());
+        Savepoint {
+            dict_index: self.dict_index,
+            sequence_id: self.sequence_id,
+        }
+    }
+
+    /// Keep the changes of a successfully unpacked message, or roll them back.
+    fn finish<T>(
+        &mut self,
+        savepoint: Savepoint,
+        unpacked: Result<T, UnpackerError>,
+    ) -> Result<T, UnpackerError> {
+        let journal = self.journal.take().unwrap_or_default();
+        if unpacked.is_err() {
+            for undo in journal.into_iter().rev() {
+                match undo {
+                    Undo::AddToDict(index, Some(entry)) => {
+                        self.dict.insert(index, entry);
+                    }
+                    Undo::AddToDict(index, None) => {
+                        self.dict.remove(&index);
+                    }
+                    Undo::LastValue(value) => self.last_value = value,
+                }
+            }
+            self.dict_index = savepoint.dict_index;
+            self.sequence_id = savepoint.sequence_id;
+        }
+        unpacked
+    }
+
+    fn trace_array_resolution(&mut self, packed_array: &[Value], new: bool, index: u64) {
+        let key = (packed_array.as_ptr() as usize, Some(packed_array.len()));
+        self.trace_resolution(key, new, index);
+    }
+
+    fn trace_resolution(&mut self, key: (usize, Option<usize>), new: bool, index: u64) {
+        let trace = match self.trace {
+            Some(ref mut trace) => trace,
+            None => return,
+        };
+        let (value, kind) = match self.dict.get(&index) {
+            Some(&(ref value, kind)) => (value.clone(), kind),
+            None => return,
+        };
+        trace.entry(key).or_default().push_back(Resolution {
+            new,
+            entry: DictEntry { index, value, kind },
+        });
+    }
+
+    /// Register a schema used to unpack positionally packed objects.
+    /// Must match the schema registered on the packer.
+    pub fn register_schema(&mut self, schema: Schema) {
+        self.schemas.retain(|s| s.id != schema.id);
+        self.schemas.push(schema);
+    }
+
+    /// Forget the dictionary and the last unpacked value, e.g. after `Packer::reset`.
+    /// Settings and registered schemas are kept.
+    pub fn reset(&mut self) {
+        self.dict = HashMap::new();
+        self.dict_index = MIN_DICT_INDEX;
+        self.sequence_id = -1;
+        self.last_value = None;
     }
 
     /// Set the maximum dictionary size. Must match the dictionary size used by the packer.
@@ -329,4 +922,151 @@ impl Unpacker {
     pub fn set_max_dict_size(&mut self, value: u64) {
         self.max_dict_size = value;
     }
+
+    /// Set the maximum length of a packed numeric sequence. Longer sequences are rejected
+    /// before they are decoded, a run count in a frame does not allocate unbounded memory.
+    /// Default - 1000000.
+    pub fn set_max_sequence_len(&mut self, value: u64) {
+        self.max_sequence_len = value;
+    }
+
+    /// Set which values are memoised. Must match the policy used by the packer.
+    /// Default - every value
+    pub fn set_memo_policy(&mut self, policy: MemoPolicy) {
+        self.memo_policy = policy;
+    }
+
+    /// Settings the packer must agree on.
+    pub fn session_config(&self) -> SessionConfig {
+        SessionConfig {
+            max_dict_size: self.max_dict_size,
+            memo_policy: self.memo_policy.clone(),
+        }
+    }
+
+    pub fn set_session_config(&mut self, config: SessionConfig) {
+        self.max_dict_size = config.max_dict_size;
+        self.memo_policy = config.memo_policy;
+    }
+
+    /// Apply the session config of a frame returned by `Packer::handshake` and start
+    /// a new session. Registered schemas are kept.
+    pub fn accept_handshake(&mut self, frame: &Value) -> Result<(), UnpackerError> {
+        let config = SessionConfig::from_handshake(frame)?;
+        self.set_session_config(config);
+        self.reset();
+        Ok(())
+    }
+}
+
+/// Replace traced nodes of a packed frame by their annotation, children before parents
+/// as they were unpacked.
+fn annotate(packed_object: &Value, trace: &mut Trace) -> Value {
+    match *packed_object {
+        Value::Array(ref arr) => annotate_array(arr, trace),
+        _ => resolve(
+            trace,
+            (packed_object as *const Value as usize, None),
+            packed_object.clone(),
+        ),
+    }
+}
+
+fn annotate_array(packed_array: &[Value], trace: &mut Trace) -> Value {
+    let packed = Value::Array(packed_array.iter().map(|v| annotate(v, trace)).collect());
+    let key = (packed_array.as_ptr() as usize, Some(packed_array.len()));
+    resolve(trace, key, packed)
+}
+
+fn resolve(trace: &mut Trace, key: (usize, Option<usize>), packed: Value) -> Value {
+    match trace
+        .get_mut(&key)
+        .and_then(|resolutions| resolutions.pop_front())
+    {
+        Some(resolution) => resolution.annotate(packed),
+        None => packed,
+    }
+}
+
+/// Decodes an array packed as first value, first delta and `[delta of delta, repeat count]` pairs.
+/// Sequences of more than `max_len` numbers are rejected.
+fn unpack_numeric_sequence(packed_numbers: &[Value], max_len: u64) -> Result<Value, UnpackerError> {
+    let wrong_sequence = || UnpackerError {
+        kind: UnpackerErrorKind::MalformedFrame,
+        cause: "wrong numeric sequence".to_owned(),
+    };
+    if packed_numbers.len() < 2 || !packed_numbers.len().is_multiple_of(2) {
+        return Err(wrong_sequence());
+    }
+
+    let mut len: u64 = 2;
+    for run in packed_numbers[2..].chunks(2) {
+        let count = run[1].as_u64().ok_or_else(wrong_sequence)?;
+        len = len.saturating_add(count);
+    }
+    if len > max_len {
+        return Err(UnpackerError {
+            kind: UnpackerErrorKind::MalformedFrame,
+            cause: format!("numeric sequence of {} numbers exceeds {}", len, max_len),
+        });
+    }
+
+    let mut value = packed_numbers[0].as_i64().ok_or_else(wrong_sequence)?;
+    let mut delta = packed_numbers[1].as_i64().ok_or_else(wrong_sequence)?;
+    let mut result: Vec<Value> = Vec::with_capacity(len as usize);
+    result.push(json!(value));
+    value = value.checked_add(delta).ok_or_else(wrong_sequence)?;
+    result.push(json!(value));
+
+    for run in packed_numbers[2..].chunks(2) {
+        let delta_of_delta = run[0].as_i64().ok_or_else(wrong_sequence)?;
+        let count = run[1].as_u64().ok_or_else(wrong_sequence)?;
+        for _ in 0..count {
+            delta = delta
+                .checked_add(delta_of_delta)
+                .ok_or_else(wrong_sequence)?;
+            value = value.checked_add(delta).ok_or_else(wrong_sequence)?;
+            result.push(json!(value));
+        }
+    }
+
+    Ok(json!(result))
+}
+
+/// Whether a frame holds a string packed by `Packer::pack_string`: its tag, the splitter id
+/// of split strings, the segments and the sequence id. A frame only starting with the same
+/// value as the tag, e.g. `[2]` for `{}`, is a regular message.
+fn is_string_frame(frame: &[Value]) -> bool {
+    let min_len = match frame.first().and_then(Value::as_i64) {
+        Some(TYPE_STRING) => 2,
+        Some(TYPE_SPLIT_STRING) if frame.get(1).is_some_and(Value::is_u64) => 3,
+        _ => return false,
+    };
+    frame.len() >= min_len && frame.last().is_some_and(Value::is_u64)
+}
+
+/// Unescaped strings starting like a number are packed numbers.
+fn looks_like_number(string: &str) -> bool {
+    let bytes = string.as_bytes();
+    let number = match bytes.first() {
+        Some(&b'-') => &bytes[1..],
+        _ => bytes,
+    };
+    match number.first() {
+        Some(c) => c.is_ascii_digit() || *c == b'.',
+        None => false,
+    }
+}
+
+fn parse_number(string: &str) -> Option<Number> {
+    if let Ok(number) = string.parse::<i64>() {
+        return Some(Number::from(number));
+    }
+    if let Ok(number) = string.parse::<u64>() {
+        return Some(Number::from(number));
+    }
+    match string.parse::<f64>() {
+        Ok(number) => Number::from_f64(number),
+        Err(_err) => None,
+    }
 }
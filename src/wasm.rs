@@ -0,0 +1,119 @@
+//! `Packer` and `Unpacker` classes for JavaScript, built with the `wasm` feature:
+//!
+//! ```text
+//! wasm-pack build --target web -- --features wasm
+//! ```
+use packer::{PackOptions, Packer};
+use serde::Serialize;
+use serde_json::Value;
+use unpacker::Unpacker;
+use wasm_bindgen::prelude::*;
+
+fn from_js(value: JsValue) -> Result<Value, JsError> {
+    match serde_wasm_bindgen::from_value(value) {
+        Ok(value) => Ok(value),
+        Err(err) => Err(JsError::new(&err.to_string())),
+    }
+}
+
+/// Objects become plain JS objects rather than `Map`s, like `JSON.parse` returns.
+fn to_js(value: &Value) -> Result<JsValue, JsError> {
+    match value.serialize(&serde_wasm_bindgen::Serializer::json_compatible()) {
+        Ok(value) => Ok(value),
+        Err(err) => Err(JsError::new(&err.to_string())),
+    }
+}
+
+#[wasm_bindgen(js_name = Packer)]
+pub struct WasmPacker {
+    packer: Packer,
+    options: PackOptions,
+}
+
+#[wasm_bindgen(js_class = Packer)]
+impl WasmPacker {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> WasmPacker {
+        WasmPacker {
+            packer: Packer::new(),
+            options: PackOptions::new(),
+        }
+    }
+
+    pub fn pack(&mut self, value: JsValue) -> Result<JsValue, JsError> {
+        match self.packer.pack(&from_js(value)?, &self.options) {
+            Ok(packed) => to_js(&packed),
+            Err(err) => Err(JsError::new(&err.to_string())),
+        }
+    }
+
+    #[wasm_bindgen(js_name = packString)]
+    pub fn pack_string(&mut self, string: &str) -> Result<JsValue, JsError> {
+        match self.packer.pack_string(string, &self.options) {
+            Ok(packed) => to_js(&packed),
+            Err(err) => Err(JsError::new(&err.to_string())),
+        }
+    }
+
+    /// Pack strings nested up to `depth` levels deep with `packString`. Default - -1 (off)
+    #[wasm_bindgen(js_name = setPackStringDepth)]
+    pub fn set_pack_string_depth(&mut self, depth: i32) {
+        self.options.pack_string_depth = depth;
+    }
+
+    #[wasm_bindgen(js_name = setMaxDictSize)]
+    pub fn set_max_dict_size(&mut self, value: u32) {
+        self.packer.set_max_dict_size(u64::from(value));
+    }
+
+    pub fn reset(&mut self) {
+        self.packer.reset();
+    }
+}
+
+impl Default for WasmPacker {
+    fn default() -> WasmPacker {
+        WasmPacker::new()
+    }
+}
+
+#[wasm_bindgen(js_name = Unpacker)]
+pub struct WasmUnpacker {
+    unpacker: Unpacker,
+}
+
+#[wasm_bindgen(js_class = Unpacker)]
+impl WasmUnpacker {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> WasmUnpacker {
+        WasmUnpacker {
+            unpacker: Unpacker::new(),
+        }
+    }
+
+    pub fn unpack(&mut self, packed: JsValue) -> Result<JsValue, JsError> {
+        match self.unpacker.unpack::<Value>(&from_js(packed)?) {
+            Ok(unpacked) => to_js(&unpacked),
+            Err(err) => Err(JsError::new(&err.cause)),
+        }
+    }
+
+    #[wasm_bindgen(js_name = unpackString)]
+    pub fn unpack_string(&mut self, packed: JsValue) -> Result<String, JsError> {
+        match self.unpacker.unpack_string(&from_js(packed)?) {
+            Ok(unpacked) => Ok(unpacked),
+            Err(err) => Err(JsError::new(&err.cause)),
+        }
+    }
+
+    #[wasm_bindgen(js_name = setMaxDictSize)]
+    pub fn set_max_dict_size(&mut self, value: u32) {
+        self.unpacker.set_max_dict_size(u64::from(value));
+    }
+}
+
+impl Default for WasmUnpacker {
+    fn default() -> WasmUnpacker {
+        WasmUnpacker::new()
+    }
+}
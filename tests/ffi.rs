@@ -0,0 +1,105 @@
+#![cfg(feature = "ffi")]
+extern crate jsonm;
+
+use jsonm::ffi::*;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::ptr;
+
+unsafe fn take_string(string: *mut c_char) -> String {
+    let result = CStr::from_ptr(string).to_str().unwrap().to_owned();
+    jsonm_free(string);
+    result
+}
+
+#[test]
+fn it_packs_and_unpacks_through_the_c_api() {
+    unsafe {
+        let packer = jsonm_packer_new();
+        let unpacker = jsonm_unpacker_new();
+        let input = CString::new(r#"{"foo":"bar","n":1}"#).unwrap();
+
+        for _i in 0..2 {
+            let mut packed = ptr::null_mut();
+            assert_eq!(
+                jsonm_pack_json(packer, input.as_ptr(), &mut packed),
+                JsonmStatus::JsonmOk
+            );
+
+            let mut unpacked = ptr::null_mut();
+            assert_eq!(
+                jsonm_unpacker_unpack_json(unpacker, packed, &mut unpacked),
+                JsonmStatus::JsonmOk
+            );
+            jsonm_free(packed);
+            assert_eq!(take_string(unpacked), r#"{"foo":"bar","n":1}"#);
+        }
+
+        let text = CString::new("line 1\nline 2").unwrap();
+        let mut packed = ptr::null_mut();
+        assert_eq!(
+            jsonm_pack_string(packer, text.as_ptr(), &mut packed),
+            JsonmStatus::JsonmOk
+        );
+        let mut unpacked = ptr::null_mut();
+        assert_eq!(
+            jsonm_unpacker_unpack_string(unpacker, packed, &mut unpacked),
+            JsonmStatus::JsonmOk
+        );
+        jsonm_free(packed);
+        assert_eq!(take_string(unpacked), "line 1\nline 2");
+
+        jsonm_packer_free(packer);
+        jsonm_unpacker_free(unpacker);
+    }
+}
+
+#[test]
+fn it_maps_unpacker_errors_to_status_codes() {
+    unsafe {
+        let unpacker = jsonm_unpacker_new();
+        let mut out = ptr::null_mut();
+
+        let frame = CString::new(r#"["foo", 3]"#).unwrap();
+        assert_eq!(
+            jsonm_unpacker_unpack_json(unpacker, frame.as_ptr(), &mut out),
+            JsonmStatus::JsonmOutOfSequence
+        );
+        assert_eq!(
+            CStr::from_ptr(jsonm_last_error()).to_str().unwrap(),
+            "message unpacked out of sequence or already unpacked"
+        );
+
+        let frame = CString::new("[7, 0]").unwrap();
+        assert_eq!(
+            jsonm_unpacker_unpack_json(unpacker, frame.as_ptr(), &mut out),
+            JsonmStatus::JsonmUnknownReference
+        );
+
+        let frame = CString::new("[-1, 42, 0]").unwrap();
+        assert_eq!(
+            jsonm_unpacker_unpack_json(unpacker, frame.as_ptr(), &mut out),
+            JsonmStatus::JsonmUnknownSchema
+        );
+
+        let frame = CString::new("{not json").unwrap();
+        assert_eq!(
+            jsonm_unpacker_unpack_json(unpacker, frame.as_ptr(), &mut out),
+            JsonmStatus::JsonmInvalidJson
+        );
+
+        let frame = CString::new("[]").unwrap();
+        assert_eq!(
+            jsonm_unpacker_unpack_string(unpacker, frame.as_ptr(), &mut out),
+            JsonmStatus::JsonmMalformedFrame
+        );
+
+        assert_eq!(
+            jsonm_unpacker_unpack_json(unpacker, ptr::null(), &mut out),
+            JsonmStatus::JsonmNullArgument
+        );
+        assert!(out.is_null());
+
+        jsonm_unpacker_free(unpacker);
+    }
+}
@@ -0,0 +1,95 @@
+#![cfg(feature = "python")]
+#[macro_use]
+extern crate serde_json;
+extern crate jsonm;
+extern crate pyo3;
+
+use jsonm::python::{to_python, to_value, PyPacker, PyUnpacker};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use std::ffi::CString;
+
+fn eval<'py>(py: Python<'py>, code: &str) -> Bound<'py, PyAny> {
+    let code = CString::new(code).unwrap();
+    py.eval(&code, None, None).unwrap()
+}
+
+#[test]
+fn it_converts_python_values_to_json() {
+    Python::initialize();
+    Python::attach(|py| {
+        let object = eval(
+            py,
+            "{'foo': 'bar', 'list': [1, -2, 2**64 - 1, 1.5, True, None], 'tuple': (1, 'a')}",
+        );
+        assert_eq!(
+            to_value(&object).unwrap(),
+            json!({
+                "foo": "bar",
+                "list": [1, -2, 18446744073709551615u64, 1.5, true, null],
+                "tuple": [1, "a"]
+            })
+        );
+
+        assert!(to_value(&eval(py, "{1: 'a'}")).is_err());
+        assert!(to_value(&eval(py, "float('nan')")).is_err());
+        assert!(to_value(&eval(py, "2**64")).is_err());
+        assert!(to_value(&eval(py, "object()")).is_err());
+    });
+}
+
+#[test]
+fn it_converts_json_to_python_values() {
+    Python::initialize();
+    Python::attach(|py| {
+        let value = json!({"b": [1, 1.5, "x", false, null], "a": {}});
+        let object = to_python(py, &value).unwrap();
+        let object = object.bind(py);
+
+        assert!(object.is_instance_of::<PyDict>());
+        assert!(object.get_item("b").unwrap().is_instance_of::<PyList>());
+        assert!(object
+            .eq(eval(py, "{'b': [1, 1.5, 'x', False, None], 'a': {}}"))
+            .unwrap());
+        assert_eq!(to_value(object).unwrap(), value);
+    });
+}
+
+#[test]
+fn it_packs_and_unpacks_python_values() {
+    Python::initialize();
+    Python::attach(|py| {
+        let mut packer = PyPacker::new();
+        let mut unpacker = PyUnpacker::new();
+        let input = eval(py, "{'foo': 'bar', 'n': [1, 2]}");
+
+        for _i in 0..2 {
+            let packed = packer.pack(py, &input).unwrap();
+            let unpacked = unpacker.unpack(py, packed.bind(py)).unwrap();
+            assert!(unpacked.bind(py).eq(&input).unwrap());
+        }
+
+        packer.reset();
+        unpacker.reset();
+        let packed = packer.pack_string(py, "line 1\nline 2").unwrap();
+        assert_eq!(
+            unpacker.unpack_string(packed.bind(py)).unwrap(),
+            "line 1\nline 2"
+        );
+
+        let packed = eval(py, "['foo', 5]");
+        assert!(unpacker.unpack(py, &packed).is_err());
+
+        // The dictionary size is kept, values are evicted on both sides alike.
+        packer.set_max_dict_size(2);
+        unpacker.set_max_dict_size(2);
+        packer.reset();
+        unpacker.reset();
+        for code in ["['a', 'b']", "['c', 'b']", "['c']"] {
+            let input = eval(py, code);
+            let packed = packer.pack(py, &input).unwrap();
+            let unpacked = unpacker.unpack(py, packed.bind(py)).unwrap();
+            assert!(unpacked.bind(py).eq(&input).unwrap());
+        }
+    });
+}
@@ -1,14 +1,39 @@
+// The original pack_string tests pass owned strings.
+#![allow(clippy::unnecessary_to_owned)]
 #[macro_use]
 extern crate serde_json;
+#[macro_use]
+extern crate serde_derive;
 extern crate jsonm;
+#[cfg(feature = "regex")]
+extern crate regex;
 
 const TYPE_ARRAY: u32 = 0;
 const TYPE_VALUE: u32 = 1;
 const TYPE_STRING: u32 = 2;
-
-use jsonm::packer::{PackOptions, Packer};
-use jsonm::unpacker::Unpacker;
+const TYPE_SCHEMA: i32 = -1;
+const TYPE_COLUMNS: i32 = -2;
+const TYPE_DELTA: i32 = -3;
+const TYPE_NUMBERS: i32 = -4;
+const TYPE_PREFIX: i32 = -5;
+const TYPE_SPLIT_STRING: i32 = -6;
+const DELTA_SET: u32 = 0;
+const DELTA_REMOVE: u32 = 1;
+
+use jsonm::dictionary::{DictEntry, EntryKind};
+use jsonm::disassembler::disassemble;
+use jsonm::packer::{PackOptions, Packer, StringSplitter};
+use jsonm::schema::{JsonmSchema, Schema};
+use jsonm::session::{MemoPolicy, SessionConfig};
+use jsonm::shared::{SharedPacker, SharedUnpacker};
+use jsonm::stats::{Metrics, PackerStats};
+use jsonm::unpacker::{Unpacker, UnpackerErrorKind};
 use serde_json::Value;
+use std::collections::BTreeMap;
+use std::panic;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 #[test]
 fn it_packs_small_integers_as_string_values() {
@@ -256,7 +281,7 @@ fn it_packs_multi_line_strings_as_separate_values_in_string_packing_mode() {
         json!([
             TYPE_STRING,
             "hello there",
-            "this is",
+            "this is\r",
             "a multi-line string",
             0
         ])
@@ -264,7 +289,7 @@ fn it_packs_multi_line_strings_as_separate_values_in_string_packing_mode() {
 
     let mut unpacker = Unpacker::new();
     let unpacked: Value = unpacker.unpack(&packed).unwrap();
-    assert_eq!(unpacked, "hello there\nthis is\na multi-line string");
+    assert_eq!(unpacked, "hello there\nthis is\r\na multi-line string");
 }
 
 #[test]
@@ -338,6 +363,41 @@ fn it_has_symmetrical_pack_string_and_unpack_string_for_strings() {
     assert_eq!(unpacked, "hello there\nthis is\na multi-line string");
 }
 
+#[test]
+fn it_packs_json_looking_lines_as_plain_strings() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let mut options = PackOptions::new();
+    options.pack_string_depth = 1;
+
+    for string in &["null\nx", "{\"a\":1}\n[1, 2]\n3", "true\nfalse"] {
+        let packed = packer.pack_string(string, &options).unwrap();
+        assert_eq!(packed[0], json!(TYPE_STRING));
+        assert_eq!(unpacker.unpack_string(&packed).unwrap(), *string);
+    }
+}
+
+#[test]
+fn it_unpacks_strings_of_messages_starting_like_a_string_frame() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let options = PackOptions::new();
+
+    for string in &["a", "b", "{}"] {
+        let packed = packer.pack_string(string, &options).unwrap();
+        assert_eq!(unpacker.unpack_string(&packed).unwrap(), *string);
+    }
+}
+
+#[test]
+fn it_errors_on_an_empty_string_frame() {
+    let mut unpacker = Unpacker::new();
+    match unpacker.unpack_string(&json!([])) {
+        Ok(_) => panic!("expected an error"),
+        Err(err) => assert_eq!(err.kind, UnpackerErrorKind::MalformedFrame),
+    };
+}
+
 #[test]
 fn it_has_no_issues_going_over_a_very_small_dictionary_size() {
     let mut packer = Packer::new();
@@ -667,10 +727,13 @@ fn it_copes_with_messages_in_wrong_order() {
 
     match unpacker.unpack(&packed3) {
         Ok(s) => s,
-        Err(err) => assert_eq!(
-            err.cause,
-            "message unpacked out of sequence or already unpacked"
-        ),
+        Err(err) => {
+            assert_eq!(
+                err.cause,
+                "message unpacked out of sequence or already unpacked"
+            );
+            assert_eq!(err.kind, UnpackerErrorKind::OutOfSequence);
+        }
     };
     match unpacker.unpack(&packed2) {
         Ok(s) => s,
@@ -814,3 +877,1490 @@ fn it_doesnot_pack_very_large_nonscalars() {
     let unpacked: Value = unpacker.unpack(&packed).unwrap();
     assert_eq!(unpacked, input);
 }
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct User {
+    id: u32,
+    name: String,
+    tags: Vec<String>,
+}
+
+impl JsonmSchema for User {
+    const SCHEMA_ID: u64 = 7;
+    const FIELDS: &'static [&'static str] = &["id", "name", "tags"];
+}
+
+#[test]
+fn it_packs_schema_objects_positionally() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    packer.register_schema(Schema::of::<User>());
+    unpacker.register_schema(Schema::of::<User>());
+
+    let user = User {
+        id: 1,
+        name: "Anna".to_owned(),
+        tags: vec!["admin".to_owned()],
+    };
+    let options = PackOptions::new();
+    let packed = packer.pack(&user, &options).unwrap();
+    assert_eq!(
+        packed,
+        json!([TYPE_SCHEMA, 7, "1", "Anna", [TYPE_ARRAY, "admin"], 0])
+    );
+    let unpacked: User = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked, user);
+
+    let packed = packer.pack(&user, &options).unwrap();
+    assert_eq!(packed, json!([TYPE_SCHEMA, 7, 3, 4, [TYPE_ARRAY, 5], 1]));
+    let unpacked: User = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked, user);
+}
+
+#[test]
+fn it_falls_back_to_key_value_packing_for_non_schema_objects() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    packer.register_schema(Schema::new(1, &["x", "y"]));
+    unpacker.register_schema(Schema::new(1, &["x", "y"]));
+
+    let options = PackOptions::new();
+    let input = json!([{"x": 1, "y": 2}, {"y": 2, "x": 1}, {"x": 1}]);
+    let packed = packer.pack(&input, &options).unwrap();
+    assert_eq!(
+        packed,
+        json!([
+            TYPE_ARRAY,
+            [TYPE_SCHEMA, 1, "1", "2"],
+            ["y", "x", 4, 3],
+            [6, 3],
+            0
+        ])
+    );
+    let unpacked: Value = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked, input);
+}
+
+#[test]
+fn it_errors_on_unknown_schema() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    packer.register_schema(Schema::new(1, &["x", "y"]));
+
+    let options = PackOptions::new();
+    let packed = packer.pack(&json!({"x": 1, "y": 2}), &options).unwrap();
+    match unpacker.unpack::<Value>(&packed) {
+        Ok(_) => panic!("expected an error"),
+        Err(err) => {
+            assert_eq!(err.cause, "unknown schema 1");
+            assert_eq!(err.kind, UnpackerErrorKind::UnknownSchema);
+        }
+    };
+}
+
+#[test]
+fn it_packs_homogeneous_object_arrays_as_columns() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let mut options = PackOptions::new();
+    options.columnar = true;
+
+    let input = json!([
+        {"id": 1, "name": "Anna", "tags": ["a"]},
+        {"id": 2, "name": "Anna", "tags": []},
+        {"id": 3, "name": "Tim", "tags": ["a", "b"]}
+    ]);
+    let packed = packer.pack(&input, &options).unwrap();
+    assert_eq!(
+        packed,
+        json!([
+            TYPE_COLUMNS,
+            ["id", "name", "tags"],
+            ["1", "2", "3"],
+            ["Anna", 9, "Tim"],
+            [[TYPE_ARRAY, "a"], [TYPE_ARRAY], [TYPE_ARRAY, 11, "b"]],
+            0
+        ])
+    );
+    let unpacked: Value = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked, input);
+
+    let packed = packer.pack(&input, &options).unwrap();
+    assert_eq!(
+        packed,
+        json!([
+            TYPE_COLUMNS,
+            [3, 4, 5],
+            [6, 7, 8],
+            [9, 9, 10],
+            [[TYPE_ARRAY, 11], [TYPE_ARRAY], [TYPE_ARRAY, 11, 12]],
+            1
+        ])
+    );
+    let unpacked: Value = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked, input);
+}
+
+#[test]
+fn it_packs_heterogeneous_object_arrays_row_by_row_in_columnar_mode() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let mut options = PackOptions::new();
+    options.columnar = true;
+
+    let input = json!({"rows": [{"a": 1, "b": 2}, {"b": 2, "a": 1}], "cols": [{"a": 1}, {"a": 2}]});
+    let packed = packer.pack(&input, &options).unwrap();
+    assert_eq!(
+        packed,
+        json!([
+            "rows",
+            "cols",
+            [TYPE_ARRAY, ["a", "b", "1", "2"], [6, 5, 8, 7]],
+            [TYPE_COLUMNS, [5], [7, 8]],
+            0
+        ])
+    );
+    let unpacked: Value = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked, input);
+}
+
+#[test]
+fn it_packs_deltas_against_the_previous_message() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let options = PackOptions::new();
+
+    let first = json!({"id": 1, "status": "idle", "pos": [10, 20], "meta": {"a": true}});
+    let packed = packer.pack(&first, &options).unwrap();
+    let unpacked: Value = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked, first);
+
+    let second = json!({"id": 1, "status": "busy", "pos": [10, 21], "extra": null});
+    let packed = packer.pack_delta(&second, &options).unwrap();
+    assert_eq!(
+        packed,
+        json!([
+            TYPE_DELTA,
+            [DELTA_REMOVE, 6],
+            [DELTA_SET, 4, "busy"],
+            [DELTA_SET, 5, -2, "21"],
+            [DELTA_SET, "extra", null],
+            1
+        ])
+    );
+    let unpacked: Value = unpacker.unpack_delta(&packed).unwrap();
+    assert_eq!(unpacked, second);
+
+    let packed = packer.pack_delta(&first, &options).unwrap();
+    let unpacked: Value = unpacker.unpack_delta(&packed).unwrap();
+    assert_eq!(unpacked, first);
+
+    let packed = packer.pack_delta(&first, &options).unwrap();
+    assert_eq!(packed, json!([TYPE_DELTA, 3]));
+    let unpacked: Value = unpacker.unpack_delta(&packed).unwrap();
+    assert_eq!(unpacked, first);
+}
+
+#[test]
+fn it_keeps_the_key_order_in_deltas() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let options = PackOptions::new();
+
+    let messages = [
+        json!({"a": 1, "b": 2, "c": 3}),
+        json!({"b": 2, "c": 3}),
+        json!({"b": 2, "c": 3, "a": 1}),
+        json!({"c": 3, "b": 2, "a": 1}),
+        json!({"c": 3, "nested": {"x": 1, "y": 2}}),
+        json!({"c": 3, "nested": {"y": 2, "x": 1}}),
+    ];
+    let packed = packer.pack(&messages[0], &options).unwrap();
+    let unpacked: Value = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked.to_string(), messages[0].to_string());
+
+    for message in &messages[1..] {
+        let packed = packer.pack_delta(message, &options).unwrap();
+        let unpacked: Value = unpacker.unpack_delta(&packed).unwrap();
+        // Equality of objects ignores the key order, compare their text.
+        assert_eq!(unpacked.to_string(), message.to_string());
+    }
+
+    // A removal is sent as such, not as the whole object.
+    let packed = packer.pack_delta(&json!({"c": 3}), &options).unwrap();
+    assert_eq!(packed[1][0], json!(DELTA_REMOVE));
+}
+
+#[test]
+fn it_packs_a_full_delta_without_a_previous_message() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let options = PackOptions::new();
+
+    let input = json!([{"foo": "bar"}, 1]);
+    let packed = packer.pack_delta(&input, &options).unwrap();
+    assert_eq!(
+        packed,
+        json!([
+            TYPE_DELTA,
+            [DELTA_SET, [TYPE_ARRAY, ["foo", "bar"], "1"]],
+            0
+        ])
+    );
+    let unpacked: Value = unpacker.unpack_delta(&packed).unwrap();
+    assert_eq!(unpacked, input);
+
+    let packed = packer.pack(&json!([{"foo": "bar"}, 1]), &options).unwrap();
+    assert_eq!(packed, json!([TYPE_ARRAY, [3, 4], 5, 1]));
+    let unpacked: Value = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked, input);
+}
+
+#[test]
+fn it_refuses_deltas_in_unpack() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let options = PackOptions::new();
+
+    let packed = packer.pack_delta(&json!({"foo": 1}), &options).unwrap();
+    match unpacker.unpack::<Value>(&packed) {
+        Ok(_) => panic!("expected an error"),
+        Err(err) => assert_eq!(err.cause, "unexpected delta, use unpack_delta instead"),
+    };
+}
+
+#[test]
+fn it_packs_numeric_sequences_with_delta_and_run_length_encoding() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let mut options = PackOptions::new();
+    options.pack_numeric_sequences = true;
+
+    let input = json!({
+        "ts": [1000, 1010, 1020, 1030, 1040, 1055, 1070, 1070],
+        "values": [5, -3],
+        "mixed": [1, 2.5],
+        "single": [7]
+    });
+    let packed = packer.pack(&input, &options).unwrap();
+    assert_eq!(
+        packed,
+        json!([
+            "ts",
+            "values",
+            "mixed",
+            "single",
+            [TYPE_NUMBERS, 1000, 10, 0, 3, 5, 1, 0, 1, -15, 1],
+            [TYPE_NUMBERS, 5, -8],
+            [TYPE_ARRAY, "1", "2.5"],
+            [TYPE_ARRAY, "7"],
+            0
+        ])
+    );
+    let unpacked: Value = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked, input);
+}
+
+#[test]
+fn it_falls_back_to_regular_arrays_when_numeric_deltas_overflow() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let mut options = PackOptions::new();
+    options.pack_numeric_sequences = true;
+
+    let input = json!([i64::MIN, i64::MAX]);
+    let packed = packer.pack(&input, &options).unwrap();
+    assert_eq!(packed[0], json!(TYPE_ARRAY));
+    let unpacked: Value = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked, input);
+}
+
+#[test]
+fn it_rejects_numeric_sequences_longer_than_the_limit() {
+    let mut unpacker = Unpacker::new();
+    let packed = json!([TYPE_NUMBERS, 0, 0, 0, 200_000_000u64, 0]);
+    let result: Result<Value, _> = unpacker.unpack(&packed);
+    assert!(result.is_err());
+
+    let packed = json!([TYPE_NUMBERS, 0, 1, 0, 9, 0, u64::MAX, 0]);
+    let result: Result<Value, _> = unpacker.unpack(&packed);
+    assert!(result.is_err());
+
+    unpacker.set_max_sequence_len(10);
+    let packed = json!([TYPE_NUMBERS, 0, 1, 0, 8, 0]);
+    let unpacked: Value = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked, json!([0, 1, 2, 3, 4, 5, 6, 7, 8, 9]));
+    let packed = json!([TYPE_NUMBERS, 0, 1, 0, 9, 1]);
+    let result: Result<Value, _> = unpacker.unpack(&packed);
+    assert!(result.is_err());
+}
+
+#[test]
+fn it_memoises_string_prefixes() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let mut options = PackOptions::new();
+    options.prefix_separator = Some('/');
+
+    let input = json!({
+        "a": "https://api.example.com/users/123",
+        "b": "https://api.example.com/users/124",
+        "c": "https://api.example.com/orders/",
+        "d": "no separator"
+    });
+    let packed = packer.pack(&input, &options).unwrap();
+    assert_eq!(
+        packed,
+        json!([
+            "a",
+            "b",
+            "c",
+            "d",
+            [TYPE_PREFIX, "https://api.example.com/users/", "~123"],
+            [TYPE_PREFIX, 7, "~124"],
+            [TYPE_PREFIX, "https://api.example.com/", "orders/"],
+            "no separator",
+            0
+        ])
+    );
+    let unpacked: Value = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked, input);
+
+    let packed = packer
+        .pack(&json!(["https://api.example.com/users/", "/"]), &options)
+        .unwrap();
+    assert_eq!(packed, json!([TYPE_ARRAY, 7, "/", 1]));
+    let unpacked: Value = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked, json!(["https://api.example.com/users/", "/"]));
+}
+
+#[test]
+fn it_splits_strings_with_configurable_splitters() {
+    let text = "GET /a 200\r\nGET /b 404\n\nPOST \"x,y\",1\n";
+    #[allow(unused_mut)]
+    let mut splitters = vec![
+        StringSplitter::LinesWithTerminators,
+        StringSplitter::Whitespace,
+        StringSplitter::Csv,
+    ];
+    #[cfg(feature = "regex")]
+    splitters.push(StringSplitter::Regex(regex::Regex::new(r"[ /]").unwrap()));
+
+    for splitter in splitters {
+        let mut packer = Packer::new();
+        let mut unpacker = Unpacker::new();
+        let mut options = PackOptions::new();
+        options.string_splitter = splitter;
+
+        for _i in 0..2 {
+            let packed = packer.pack_string(text, &options).unwrap();
+            assert_eq!(packed[0], json!(TYPE_SPLIT_STRING));
+            let unpacked = unpacker.unpack_string(&packed).unwrap();
+            assert_eq!(unpacked, text);
+        }
+    }
+}
+
+#[test]
+fn it_encodes_the_string_splitter_in_the_frame() {
+    let mut packer = Packer::new();
+    let mut options = PackOptions::new();
+    options.string_splitter = StringSplitter::Whitespace;
+
+    let packed = packer.pack_string("to be or not to be", &options).unwrap();
+    assert_eq!(
+        packed,
+        json!([
+            TYPE_SPLIT_STRING,
+            2,
+            "to",
+            " ",
+            "be",
+            4,
+            "or",
+            4,
+            "not",
+            4,
+            3,
+            4,
+            5,
+            0
+        ])
+    );
+
+    options.string_splitter = StringSplitter::Csv;
+    let packed = packer.pack_string("a,\"b,c\"\nd", &options).unwrap();
+    assert_eq!(
+        packed,
+        json!([TYPE_SPLIT_STRING, 3, "a,", "\"b,c\"\n", "d", 1])
+    );
+}
+
+fn all_strings(alphabet: &[&str], max_len: usize) -> Vec<String> {
+    let mut result = vec![String::new()];
+    let mut last = vec![String::new()];
+    for _len in 0..max_len {
+        let mut next = Vec::new();
+        for prefix in &last {
+            for c in alphabet {
+                next.push(prefix.clone() + c);
+            }
+        }
+        result.extend(next.iter().cloned());
+        last = next;
+    }
+    result
+}
+
+#[test]
+fn it_reconstructs_arbitrary_strings_byte_for_byte() {
+    let alphabet = ["a", "1", "-", "~", "\n", "\r", "\"", "}"];
+    for splitter in [StringSplitter::Lines, StringSplitter::LinesWithTerminators] {
+        let mut packer = Packer::new();
+        let mut unpacker = Unpacker::new();
+        let mut options = PackOptions::new();
+        options.string_splitter = splitter;
+
+        for string in all_strings(&alphabet, 4) {
+            let packed = packer.pack_string(&string, &options).unwrap();
+            let unpacked = unpacker.unpack_string(&packed).unwrap();
+            assert_eq!(unpacked, string, "packed as {}", packed);
+
+            let mut fresh_unpacker = Unpacker::new();
+            let mut fresh_packer = Packer::new();
+            let packed = fresh_packer.pack_string(&string, &options).unwrap();
+            let unpacked = fresh_unpacker.unpack_string(&packed).unwrap();
+            assert_eq!(unpacked, string, "packed as {}", packed);
+        }
+    }
+}
+
+#[test]
+fn it_reconstructs_trailing_newlines_and_mixed_line_endings() {
+    let strings = [
+        "line\n",
+        "line\n\n",
+        "\nline",
+        "\r\n",
+        "a\r\nb\nc\r\n\r\n",
+        "{\"a\": 1}\n",
+        "[1, 2]",
+        "-1\n-2",
+        "18446744073709551615",
+        "1e100",
+        "{}",
+    ];
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let options = PackOptions::new();
+    for string in strings.iter() {
+        let packed = packer.pack_string(string, &options).unwrap();
+        let unpacked = unpacker.unpack_string(&packed).unwrap();
+        assert_eq!(&unpacked, string);
+    }
+
+    let mut packer = Packer::new();
+    let packed = packer.pack_string("line\r\n\n", &options).unwrap();
+    assert_eq!(packed, json!([TYPE_STRING, "line\r", "", 4, 0]));
+}
+
+#[test]
+fn it_escapes_strings_that_look_like_negative_numbers() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let options = PackOptions::new();
+    let packed = packer.pack(&json!(["-1", -1, "-a"]), &options).unwrap();
+    assert_eq!(packed, json!([TYPE_ARRAY, "~-1", "-1", "-a", 0]));
+    let unpacked: Value = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked, json!(["-1", -1, "-a"]));
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+struct BorrowedUser<'a> {
+    name: &'a str,
+    role: &'a str,
+}
+
+#[test]
+fn it_unpacks_into_borrowed_types() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let options = PackOptions::new();
+
+    let input = json!({"name": "Anna", "role": "admin"});
+    for _i in 0..2 {
+        let packed = packer.pack(&input, &options).unwrap();
+        let user: BorrowedUser = unpacker.unpack_borrowed(&packed).unwrap();
+        assert_eq!(
+            user,
+            BorrowedUser {
+                name: "Anna",
+                role: "admin"
+            }
+        );
+    }
+
+    let packed = packer
+        .pack(&json!({"name": "Tim", "role": 7}), &options)
+        .unwrap();
+    match unpacker.unpack_borrowed::<BorrowedUser>(&packed) {
+        Ok(_) => panic!("expected an error"),
+        Err(err) => assert_eq!(err.cause, "unable to unpack to specific type"),
+    };
+}
+
+#[test]
+fn it_collects_packer_stats() {
+    let mut packer = Packer::new();
+    let options = PackOptions::new();
+    packer.set_measure_sizes(true);
+
+    let input = json!({"foo": "bar", "baz": 1});
+    for _i in 0..3 {
+        packer.pack(&input, &options).unwrap();
+    }
+
+    let stats = packer.stats();
+    assert_eq!(stats.messages, 3);
+    assert_eq!(stats.dict_misses, 4);
+    assert_eq!(stats.dict_hits, 8);
+    assert_eq!(stats.complex_object_hits, 1);
+    assert_eq!(stats.evictions, 0);
+    assert_eq!(stats.sequence_id, 2);
+    assert_eq!(stats.raw_bytes, 3 * input.to_string().len() as u64);
+    assert!(stats.compression_ratio() < 1.0);
+}
+
+#[test]
+fn it_counts_dictionary_evictions() {
+    let mut packer = Packer::new();
+    let options = PackOptions::new();
+    packer.set_max_dict_size(2);
+
+    packer.pack(&json!(["a", "b", "a", "c"]), &options).unwrap();
+
+    let stats = packer.stats();
+    assert_eq!(stats.dict_hits, 1);
+    assert_eq!(stats.dict_misses, 3);
+    assert_eq!(stats.evictions, 1);
+    assert_eq!(stats.raw_bytes, 0);
+    assert_eq!(stats.hit_rate(), 0.25);
+}
+
+#[derive(Clone, Default)]
+struct RecordingMetrics {
+    messages: Arc<Mutex<Vec<PackerStats>>>,
+}
+
+impl Metrics for RecordingMetrics {
+    fn on_pack(&self, message: &PackerStats, _totals: &PackerStats) {
+        self.messages.lock().unwrap().push(*message);
+    }
+}
+
+#[test]
+fn it_reports_per_message_stats_to_metrics() {
+    let mut packer = Packer::new();
+    let options = PackOptions::new();
+    let metrics = RecordingMetrics::default();
+    packer.set_metrics(metrics.clone());
+
+    packer.pack(&json!(["a", "b"]), &options).unwrap();
+    packer.pack_string("a\nb", &options).unwrap();
+
+    let messages = metrics.messages.lock().unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].messages, 1);
+    assert_eq!(messages[0].dict_misses, 2);
+    assert_eq!(messages[1].dict_hits, 2);
+    assert_eq!(messages[1].dict_misses, 0);
+    assert_eq!(messages[1].raw_bytes, 3);
+    assert_eq!(messages[1].sequence_id, 1);
+}
+
+#[test]
+fn it_exposes_matching_dictionaries() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let options = PackOptions::new();
+
+    let input = json!({"foo": "bar", "n": 1});
+    for _i in 0..2 {
+        let packed = packer.pack(&input, &options).unwrap();
+        let _: Value = unpacker.unpack(&packed).unwrap();
+    }
+
+    let expected = vec![
+        DictEntry {
+            index: 3,
+            value: json!("foo"),
+            kind: EntryKind::Key,
+        },
+        DictEntry {
+            index: 4,
+            value: json!("n"),
+            kind: EntryKind::Key,
+        },
+        DictEntry {
+            index: 5,
+            value: json!("bar"),
+            kind: EntryKind::Value,
+        },
+        DictEntry {
+            index: 6,
+            value: json!(1),
+            kind: EntryKind::Value,
+        },
+        DictEntry {
+            index: 7,
+            value: input.clone(),
+            kind: EntryKind::ComplexObject,
+        },
+    ];
+    assert_eq!(packer.dictionary(), expected);
+    assert_eq!(unpacker.dictionary(), expected);
+}
+
+#[test]
+fn it_explains_packed_frames() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let options = PackOptions::new();
+
+    let packed = packer.pack(&json!({"foo": "bar"}), &options).unwrap();
+    assert_eq!(
+        unpacker.explain(&packed).unwrap(),
+        json!([
+            {"$new": 3, "kind": "key", "value": "foo"},
+            {"$new": 4, "kind": "value", "value": "bar"},
+            0
+        ])
+    );
+    let _: Value = unpacker.unpack(&packed).unwrap();
+
+    let packed = packer
+        .pack(&json!([{"foo": "bar"}, "bar"]), &options)
+        .unwrap();
+    assert_eq!(
+        unpacker.explain(&packed).unwrap(),
+        json!([
+            TYPE_ARRAY,
+            {
+                "$new": 5,
+                "kind": "complex_object",
+                "value": {"foo": "bar"},
+                "packed": [
+                    {"$ref": 3, "kind": "key", "value": "foo"},
+                    {"$ref": 4, "kind": "value", "value": "bar"}
+                ]
+            },
+            {"$ref": 4, "kind": "value", "value": "bar"},
+            1
+        ])
+    );
+
+    // Explaining does not consume the frame.
+    let unpacked: Value = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked, json!([{"foo": "bar"}, "bar"]));
+}
+
+#[test]
+fn it_explains_a_memoised_top_level_object() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let options = PackOptions::new();
+
+    let packed = packer.pack(&json!({"foo": "bar"}), &options).unwrap();
+    let _: Value = unpacker.unpack(&packed).unwrap();
+
+    let packed = packer.pack(&json!({"foo": "bar"}), &options).unwrap();
+    assert_eq!(packed, json!([3, 4, 1]));
+    assert_eq!(
+        unpacker.explain(&packed).unwrap(),
+        json!([
+            {
+                "$new": 5,
+                "kind": "complex_object",
+                "value": {"foo": "bar"},
+                "packed": [
+                    {"$ref": 3, "kind": "key", "value": "foo"},
+                    {"$ref": 4, "kind": "value", "value": "bar"}
+                ]
+            },
+            1
+        ])
+    );
+}
+
+#[test]
+fn it_disassembles_packed_frames() {
+    let mut packer = Packer::new();
+    let options = PackOptions::new();
+
+    let frames = vec![
+        packer.pack(&json!({"foo": "bar"}), &options).unwrap(),
+        packer
+            .pack(&json!([{"foo": "bar"}, "1", 2]), &options)
+            .unwrap(),
+        packer.pack_string("a\nb", &options).unwrap(),
+        packer.pack(&json!({"x": "y"}), &options).unwrap(),
+        packer.pack(&json!({"x": "y"}), &options).unwrap(),
+    ];
+
+    let tree = disassemble(&mut Unpacker::new(), &frames).unwrap();
+    assert_eq!(
+        tree,
+        r#"sequence 0
+  OBJECT
+    new #3 key "foo"
+      new #4 value "bar"
+sequence 1
+  TYPE_ARRAY
+    new #5 complex_object {"foo":"bar"}
+      OBJECT
+        ref #3 key "foo"
+          ref #4 value "bar"
+    new #6 value "1" (packed "~1")
+    new #7 value 2 (packed "2")
+sequence 2
+  TYPE_STRING
+    new #8 value "a"
+    new #9 value "b"
+sequence 3
+  OBJECT
+    new #10 key "x"
+      new #11 value "y"
+sequence 4
+  new #12 complex_object {"x":"y"}
+    OBJECT
+      ref #10 key "x"
+        ref #11 value "y"
+"#
+    );
+}
+
+fn assert_shareable<T: Clone + Send + Sync>() {}
+
+#[test]
+fn it_shares_a_session_between_threads() {
+    assert_shareable::<SharedPacker>();
+    assert_shareable::<SharedUnpacker>();
+
+    let packer = SharedPacker::new();
+    let (sender, receiver) = mpsc::channel();
+    let producers: Vec<_> = (0..4)
+        .map(|producer| {
+            let packer = packer.clone();
+            let sender = sender.clone();
+            thread::spawn(move || {
+                let options = PackOptions::new();
+                for i in 0..50 {
+                    let message = json!({"producer": producer, "i": i % 5});
+                    packer
+                        .pack_then(&message, &options, |packed| {
+                            sender.send((message.clone(), packed)).unwrap()
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+    for producer in producers {
+        producer.join().unwrap();
+    }
+
+    let unpacker = SharedUnpacker::new();
+    let mut count = 0;
+    for (message, packed) in receiver {
+        let unpacked: Value = unpacker.unpack(&packed).unwrap();
+        assert_eq!(unpacked, message);
+        count += 1;
+    }
+    assert_eq!(count, 200);
+    assert_eq!(packer.stats().sequence_id, 199);
+}
+
+#[test]
+fn it_recovers_a_shared_packer_after_a_panic() {
+    let packer = SharedPacker::new();
+    let options = PackOptions::new();
+
+    let poisoner = packer.clone();
+    let result = panic::catch_unwind(move || {
+        poisoner
+            .with(|_packer| panic!("failed while packing"))
+            .unwrap()
+    });
+    assert!(result.is_err());
+    match packer.pack(&json!({"foo": "bar"}), &options) {
+        Ok(_) => panic!("expected an error"),
+        Err(err) => assert_eq!(err.cause, "packer poisoned by a panic, reset it first"),
+    }
+
+    packer.reset();
+    let packed = packer.pack(&json!({"foo": "bar"}), &options).unwrap();
+    let unpacked: Value = Unpacker::new().unpack(&packed).unwrap();
+    assert_eq!(unpacked, json!({"foo": "bar"}));
+}
+
+#[cfg(feature = "rayon")]
+fn large_document(seed: usize) -> Value {
+    let rows: Vec<Value> = (0..120)
+        .map(|i| {
+            json!({
+                "id": i,
+                "name": format!("user {}", (i + seed) % 40),
+                "path": format!("/api/v1/users/{}", i % 7),
+                "tags": ["a", "-1", "~x", format!("tag{}", i % 3)],
+                "active": i % 2 == 0,
+                "score": (i as f64) / 4.0,
+                "meta": null,
+                "lines": "first\nsecond",
+                "history": [i, i + 1, i + 2, i + 3],
+                "nested": {"deep": [{"k": i % 5}, [1, "x", null]]},
+                "wide": {"a": 1, "b": 2, "c": 3, "d": 4, "e": 5, "f": 6, "g": 7}
+            })
+        })
+        .collect();
+    json!({"rows": rows, "total": 120, "matrix": [[1, 2], ["a", "b"], [null, true]]})
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn it_packs_large_arrays_in_parallel_like_sequentially() {
+    let variants = vec![
+        PackOptions::new(),
+        PackOptions {
+            pack_string_depth: 2,
+            ..PackOptions::new()
+        },
+        PackOptions {
+            prefix_separator: Some('/'),
+            canonical_object_keys: true,
+            ..PackOptions::new()
+        },
+        PackOptions {
+            columnar: true,
+            pack_numeric_sequences: true,
+            ..PackOptions::new()
+        },
+    ];
+
+    let configs = vec![
+        SessionConfig::new(),
+        SessionConfig {
+            max_dict_size: 50,
+            ..SessionConfig::new()
+        },
+        SessionConfig {
+            memo_policy: MemoPolicy {
+                min_len: 3,
+                max_len: Some(12),
+                memoise_constants: false,
+                max_complex_object_size: 24,
+                memoise_nested_arrays: true,
+                memoise_arrays: true,
+            },
+            ..SessionConfig::new()
+        },
+    ];
+
+    for (variant, options) in variants.into_iter().enumerate() {
+        for config in &configs {
+            let mut sequential = Packer::new();
+            let mut parallel = Packer::new();
+            let mut unpacker = Unpacker::new();
+            sequential.set_session_config(config.clone());
+            parallel.set_session_config(config.clone());
+            unpacker.set_session_config(config.clone());
+            let schema = Schema::new(1, &["k"]);
+            sequential.register_schema(schema.clone());
+            parallel.register_schema(schema.clone());
+            unpacker.register_schema(schema);
+
+            let parallel_options = PackOptions {
+                parallel_min_array_len: Some(2),
+                ..options.clone()
+            };
+            for seed in 0..3 {
+                let input = large_document(seed);
+                let expected = sequential.pack(&input, &options).unwrap();
+                let packed = parallel.pack(&input, &parallel_options).unwrap();
+                assert_eq!(packed, expected, "options variant {}", variant);
+
+                let unpacked: Value = unpacker.unpack(&packed).unwrap();
+                assert_eq!(unpacked, input);
+            }
+            assert_eq!(parallel.dictionary(), sequential.dictionary());
+            assert_eq!(parallel.stats(), sequential.stats());
+        }
+    }
+}
+
+#[test]
+fn it_reports_serialization_errors_instead_of_panicking() {
+    let mut packer = Packer::new();
+    let options = PackOptions::new();
+
+    let mut input: BTreeMap<(u8, u8), u8> = BTreeMap::new();
+    input.insert((1, 2), 3);
+    match packer.pack(&input, &options) {
+        Ok(_) => panic!("expected an error"),
+        Err(err) => assert!(err.cause.starts_with("unable to serialize")),
+    }
+}
+
+#[test]
+fn it_packs_every_array_element() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let options = PackOptions::new();
+
+    let input = json!([
+        1,
+        9007199254740993u64,
+        -9223372036854775808i64,
+        {"a": [18446744073709551615u64, null, "x"]},
+        [[], {}],
+        null
+    ]);
+    for _i in 0..2 {
+        let packed = packer.pack(&input, &options).unwrap();
+        // Type tag, one packed value per element and the sequence id.
+        assert_eq!(packed.as_array().unwrap().len(), 8);
+        let unpacked: Value = unpacker.unpack(&packed).unwrap();
+        assert_eq!(unpacked, input);
+    }
+}
+
+#[test]
+fn it_rejects_unsafe_integers_in_strict_mode() {
+    let mut packer = Packer::new();
+    let options = PackOptions {
+        strict: true,
+        ..PackOptions::new()
+    };
+
+    let input = json!({"rows": [{"id": 1}, {"id": 9007199254740993u64}]});
+    match packer.pack(&input, &options) {
+        Ok(_) => panic!("expected an error"),
+        Err(err) => {
+            assert_eq!(
+                err.cause,
+                "integer 9007199254740993 is not exactly representable as a double"
+            );
+            assert_eq!(err.path, "/rows/1/id");
+            assert_eq!(
+                err.to_string(),
+                "PackerError: integer 9007199254740993 is not exactly representable as a double at /rows/1/id"
+            );
+        }
+    }
+
+    let sequence_options = PackOptions {
+        pack_numeric_sequences: true,
+        ..options.clone()
+    };
+    let input = json!({"a/b": [1, 2, -9007199254740993i64]});
+    match packer.pack(&input, &sequence_options) {
+        Ok(_) => panic!("expected an error"),
+        Err(err) => assert_eq!(err.path, "/a~1b/2"),
+    }
+
+    packer
+        .pack(&json!({"list": [1, 2], "n": 9007199254740991u64}), &options)
+        .unwrap();
+    match packer.pack_delta(&json!({"list": [1, 1u64 << 60], "n": 1}), &options) {
+        Ok(_) => panic!("expected an error"),
+        Err(err) => assert_eq!(err.path, "/list/1"),
+    }
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn it_reports_strict_errors_in_parallel_like_sequentially() {
+    let options = PackOptions {
+        strict: true,
+        ..PackOptions::new()
+    };
+    let parallel_options = PackOptions {
+        parallel_min_array_len: Some(2),
+        ..options.clone()
+    };
+
+    let input = json!({"rows": [{"id": 1}, {"id": [2, 1u64 << 60]}]});
+    let expected = Packer::new().pack(&input, &options).unwrap_err();
+    let err = Packer::new().pack(&input, &parallel_options).unwrap_err();
+    assert_eq!(err.path, "/rows/1/id/1");
+    assert_eq!(err.path, expected.path);
+    assert_eq!(err.cause, expected.cause);
+}
+
+#[test]
+fn it_rolls_back_a_failed_pack() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    packer.set_max_dict_size(6);
+    unpacker.set_max_dict_size(6);
+    let options = PackOptions {
+        strict: true,
+        ..PackOptions::new()
+    };
+
+    let first = json!({"a": "x", "b": "y"});
+    let packed = packer.pack(&first, &options).unwrap();
+    assert_eq!(unpacker.unpack::<Value>(&packed).unwrap(), first);
+    let dictionary = packer.dictionary();
+    let stats = packer.stats();
+
+    // Memoises and evicts entries before reaching the unsafe integer.
+    let input = json!({"c": "z", "d": ["w", "v", "u"], "e": 9007199254740993u64});
+    assert!(packer.pack(&input, &options).is_err());
+    assert_eq!(packer.dictionary(), dictionary);
+    assert_eq!(packer.stats(), stats);
+
+    let input = json!({"a": "x", "c": "z"});
+    let packed = packer.pack(&input, &options).unwrap();
+    assert_eq!(unpacker.unpack::<Value>(&packed).unwrap(), input);
+    assert_eq!(packer.dictionary(), unpacker.dictionary());
+}
+
+#[test]
+fn it_commits_or_aborts_pack_transactions() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let options = PackOptions::new();
+
+    let first = json!({"foo": "bar"});
+    let packed = packer.pack_delta(&first, &options).unwrap();
+    assert_eq!(unpacker.unpack_delta::<Value>(&packed).unwrap(), first);
+    let dictionary = packer.dictionary();
+
+    let mut transaction = packer.transaction();
+    transaction
+        .pack(&json!(["never", "sent"]), &options)
+        .unwrap();
+    transaction
+        .pack_delta(&json!({"foo": "baz"}), &options)
+        .unwrap();
+    transaction.abort();
+    assert_eq!(packer.dictionary(), dictionary);
+
+    {
+        let mut transaction = packer.transaction();
+        transaction
+            .pack_string("dropped\nwithout commit", &options)
+            .unwrap();
+    }
+    assert_eq!(packer.dictionary(), dictionary);
+
+    let second = json!({"foo": "qux"});
+    let mut transaction = packer.transaction();
+    let packed = transaction.pack_delta(&second, &options).unwrap();
+    transaction.commit();
+    assert_eq!(unpacker.unpack_delta::<Value>(&packed).unwrap(), second);
+    assert_eq!(packed.as_array().unwrap().last(), Some(&json!(1)));
+
+    let third = json!({"foo": "bar", "baz": "qux"});
+    let packed = packer.pack_delta(&third, &options).unwrap();
+    assert_eq!(unpacker.unpack_delta::<Value>(&packed).unwrap(), third);
+    assert_eq!(packer.dictionary(), unpacker.dictionary());
+}
+
+#[test]
+fn it_leaves_the_unpacker_unchanged_on_error() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let options = PackOptions::new();
+
+    let first = json!({"id": 1, "name": "Anna", "tags": ["a"]});
+    let packed = packer.pack(&first, &options).unwrap();
+    let user: User = unpacker.unpack(&packed).unwrap();
+    assert_eq!(user.name, "Anna");
+    let dictionary = unpacker.dictionary();
+
+    // Not a User, the frame is not consumed and can be unpacked into another type.
+    let second = json!({"id": "two", "name": "Bob", "tags": []});
+    let packed = packer.pack(&second, &options).unwrap();
+    assert!(unpacker.unpack::<User>(&packed).is_err());
+    assert_eq!(unpacker.dictionary(), dictionary);
+    assert_eq!(unpacker.unpack::<Value>(&packed).unwrap(), second);
+
+    // New entries before an unknown reference are dropped.
+    let third = json!({"id": 3, "name": "Carl"});
+    let packed = packer.pack(&third, &options).unwrap();
+    let dictionary = unpacker.dictionary();
+    assert_eq!(packed, json!([3, 4, "3", "Carl", 2]));
+    let corrupt = json!([3, 4, "new", 999, 2]);
+    assert!(unpacker.unpack::<Value>(&corrupt).is_err());
+    assert_eq!(unpacker.dictionary(), dictionary);
+    assert_eq!(unpacker.unpack::<Value>(&packed).unwrap(), third);
+
+    let fourth = json!({"id": 4, "name": "Carl"});
+    let packed = packer.pack_delta(&fourth, &options).unwrap();
+    let mut corrupt = packed.clone();
+    corrupt
+        .as_array_mut()
+        .unwrap()
+        .insert(2, json!([DELTA_SET, 999, "x"]));
+    assert!(unpacker.unpack_delta::<Value>(&corrupt).is_err());
+    assert_eq!(unpacker.unpack_delta::<Value>(&packed).unwrap(), fourth);
+    assert_eq!(packer.dictionary(), unpacker.dictionary());
+}
+
+#[test]
+fn it_deserializes_an_unpacked_value_into_several_types() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let options = PackOptions::new();
+
+    let input = json!({"id": 7, "name": "Anna", "tags": ["admin"]});
+    let packed = packer.pack(&input, &options).unwrap();
+    unpacker.unpack_value(&packed).unwrap();
+    let packed = packer.pack(&input, &options).unwrap();
+    assert_eq!(unpacker.unpack_value(&packed).unwrap(), &input);
+
+    assert!(unpacker.deserialize_last_value::<Vec<String>>().is_err());
+    let user: User = unpacker.deserialize_last_value().unwrap();
+    assert_eq!(user.id, 7);
+    let fields: BTreeMap<String, Value> = unpacker.deserialize_last_value().unwrap();
+    assert_eq!(fields.len(), 3);
+
+    // Already consumed.
+    assert!(unpacker.unpack_value(&packed).is_err());
+    let packed = packer.pack(&input, &options).unwrap();
+    assert_eq!(unpacker.unpack_value(&packed).unwrap(), &input);
+}
+
+#[test]
+fn it_memoises_values_allowed_by_the_memo_policy() {
+    let config = SessionConfig {
+        memo_policy: MemoPolicy {
+            min_len: 3,
+            max_len: Some(8),
+            memoise_constants: false,
+            ..MemoPolicy::new()
+        },
+        ..SessionConfig::new()
+    };
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    packer.set_session_config(config.clone());
+    unpacker.set_session_config(config);
+    let options = PackOptions::new();
+
+    let input = json!({
+        "a": "x",
+        "b": "abcd",
+        "c": 7,
+        "d": 12345,
+        "e": true,
+        "f": null,
+        "g": "much longer than eight",
+        "h": "42"
+    });
+    let packed = packer.pack(&input, &options).unwrap();
+    assert_eq!(
+        packed,
+        json!([
+            "a",
+            "b",
+            "c",
+            "d",
+            "e",
+            "f",
+            "g",
+            "h",
+            "x",
+            "abcd",
+            "7",
+            "12345",
+            true,
+            null,
+            "much longer than eight",
+            "~42",
+            0
+        ])
+    );
+    assert_eq!(unpacker.unpack::<Value>(&packed).unwrap(), input);
+
+    let packed = packer.pack(&input, &options).unwrap();
+    assert_eq!(
+        packed,
+        json!([
+            3,
+            4,
+            5,
+            6,
+            7,
+            8,
+            9,
+            10,
+            "x",
+            11,
+            "7",
+            12,
+            true,
+            null,
+            "much longer than eight",
+            "~42",
+            1
+        ])
+    );
+    assert_eq!(unpacker.unpack::<Value>(&packed).unwrap(), input);
+    assert_eq!(packer.dictionary(), unpacker.dictionary());
+    assert_eq!(packer.dictionary().len(), 10);
+}
+
+#[test]
+fn it_agrees_on_the_session_config_in_a_handshake() {
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    let options = PackOptions::new();
+    packer
+        .pack(&json!(["before", "handshake"]), &options)
+        .unwrap();
+
+    packer.set_session_config(SessionConfig {
+        max_dict_size: 4,
+        memo_policy: MemoPolicy {
+            min_len: 2,
+            max_len: None,
+            memoise_constants: false,
+            ..MemoPolicy::new()
+        },
+    });
+    let handshake = packer.handshake();
+    assert_eq!(
+        handshake,
+        json!([-7, {
+            "max_dict_size": 4,
+            "min_len": 2,
+            "max_len": null,
+            "memoise_constants": false,
+            "max_complex_object_size": 12,
+            "memoise_nested_arrays": false,
+            "memoise_arrays": false
+        }])
+    );
+    unpacker.accept_handshake(&handshake).unwrap();
+    assert_eq!(unpacker.session_config(), packer.session_config());
+
+    let mut frames = Vec::new();
+    for input in &[
+        json!(["a", "bb", "cc", false]),
+        json!(["bb", "dd", "ee", "ff"]),
+    ] {
+        let packed = packer.pack(input, &options).unwrap();
+        assert_eq!(&unpacker.unpack::<Value>(&packed).unwrap(), input);
+        frames.push(packed);
+    }
+    assert_eq!(packer.dictionary(), unpacker.dictionary());
+
+    let mut dump = Unpacker::new();
+    let handshake_settings = handshake[1].to_string();
+    let text = disassemble(&mut dump, &[handshake]).unwrap();
+    assert_eq!(text, format!("TYPE_HANDSHAKE {}\n", handshake_settings));
+    assert!(disassemble(&mut dump, &frames).is_ok());
+
+    assert!(unpacker
+        .accept_handshake(&json!([-7, {"min_len": "2"}]))
+        .is_err());
+    assert!(unpacker.accept_handshake(&json!(["a", 0])).is_err());
+    assert_eq!(
+        SessionConfig::from_handshake(&json!([-7, {}])).unwrap(),
+        SessionConfig::new()
+    );
+}
+
+#[test]
+fn it_memoises_objects_with_nested_arrays_as_configured() {
+    let input = json!([
+        {"id": "u1", "name": "Anna", "at": [52.5, 13.4]},
+        {"id": "u1", "name": "Anna", "at": [52.5, 13.4]}
+    ]);
+    let options = PackOptions::new();
+
+    // Repeated objects are memoised as a whole once all their keys and values are references.
+    let mut packer = Packer::new();
+    packer.pack(&input, &options).unwrap();
+    let packed = packer.pack(&input, &options).unwrap();
+    assert_eq!(
+        packed,
+        json!([0, [3, 4, 5, 6, 7, [0, 8, 9]], [3, 4, 5, 6, 7, [0, 8, 9]], 1])
+    );
+
+    let config = SessionConfig {
+        memo_policy: MemoPolicy {
+            max_complex_object_size: 8,
+            memoise_nested_arrays: true,
+            ..MemoPolicy::new()
+        },
+        ..SessionConfig::new()
+    };
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    packer.set_session_config(config.clone());
+    unpacker.set_session_config(config);
+    for _ in 0..3 {
+        let packed = packer.pack(&input, &options).unwrap();
+        assert_eq!(unpacker.unpack::<Value>(&packed).unwrap(), input);
+    }
+    let packed = packer.pack(&input, &options).unwrap();
+    assert_eq!(packed, json!([0, 10, 10, 3]));
+    assert_eq!(unpacker.unpack::<Value>(&packed).unwrap(), input);
+    assert_eq!(packer.dictionary(), unpacker.dictionary());
+
+    // Six keys and values plus two coordinates exceed a size of 7.
+    let mut packer = Packer::new();
+    packer.set_memo_policy(MemoPolicy {
+        max_complex_object_size: 7,
+        memoise_nested_arrays: true,
+        ..MemoPolicy::new()
+    });
+    for _ in 0..3 {
+        packer.pack(&input, &options).unwrap();
+    }
+    let packed = packer.pack(&input, &options).unwrap();
+    assert_eq!(packed[1], json!([3, 4, 5, 6, 7, [0, 8, 9]]));
+}
+
+#[test]
+fn it_memoises_repeated_arrays_as_configured() {
+    let config = SessionConfig {
+        memo_policy: MemoPolicy {
+            memoise_arrays: true,
+            ..MemoPolicy::new()
+        },
+        ..SessionConfig::new()
+    };
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+    packer.set_session_config(config.clone());
+    unpacker.set_session_config(config);
+    let options = PackOptions::new();
+
+    let input = json!({"tags": ["a", "b"], "at": [[1, 2], [1, 2]], "empty": [null]});
+    // Arrays are memoised once all their elements are references.
+    let expected = [
+        json!([
+            "tags",
+            "at",
+            "empty",
+            [0, "a", "b"],
+            [0, [0, "1", "2"], [0, 8, 9]],
+            [0, null],
+            0
+        ]),
+        json!([3, 4, 5, [0, 6, 7], [0, 10, 10], [0, null], 1]),
+        json!([3, 4, 5, 11, 12, [0, null], 2]),
+    ];
+    for expected in &expected {
+        let packed = packer.pack(&input, &options).unwrap();
+        assert_eq!(&packed, expected);
+        assert_eq!(unpacker.unpack::<Value>(&packed).unwrap(), input);
+    }
+    assert_eq!(packer.dictionary(), unpacker.dictionary());
+
+    // A repeated message that is a single array packs to a single reference.
+    let input = json!(["x", "y"]);
+    for _ in 0..3 {
+        let packed = packer.pack(&input, &options).unwrap();
+        assert_eq!(unpacker.unpack::<Value>(&packed).unwrap(), input);
+    }
+    let packed = packer.pack(&input, &options).unwrap();
+    assert_eq!(packed, json!([TYPE_VALUE, 15, 6]));
+    assert_eq!(unpacker.unpack::<Value>(&packed).unwrap(), input);
+
+    // Strings split into segments are never memoised as arrays.
+    for _ in 0..2 {
+        let packed = packer.pack_string("line\nline", &options).unwrap();
+        assert_eq!(packed[0], json!(TYPE_STRING));
+        assert_eq!(unpacker.unpack_string(&packed).unwrap(), "line\nline");
+    }
+    assert_eq!(packer.dictionary(), unpacker.dictionary());
+}
+
+#[test]
+fn it_memoises_objects_regardless_of_key_order() {
+    let input = json!([{"a": "x", "b": "y"}, {"b": "y", "a": "x"}]);
+    let options = PackOptions {
+        canonical_object_keys: true,
+        ..PackOptions::new()
+    };
+    let mut packer = Packer::new();
+    let mut unpacker = Unpacker::new();
+
+    let mut frames = Vec::new();
+    for _ in 0..3 {
+        let packed = packer.pack(&input, &options).unwrap();
+        let unpacked: Value = unpacker.unpack(&packed).unwrap();
+        // Equality of objects ignores the key order, compare their text.
+        assert_eq!(unpacked.to_string(), input.to_string());
+        frames.push(packed);
+    }
+    // The second object is memoised first, with all its keys and values known.
+    assert_eq!(frames[1], json!([0, [-8, 7, 1, 0], 7, 1]));
+    assert_eq!(frames[2], json!([0, [-8, 7, 1, 0], 7, 2]));
+    assert_eq!(packer.dictionary(), unpacker.dictionary());
+
+    let reordered = json!({"a": "x", "b": "y"});
+    let packed = packer.pack(&reordered, &options).unwrap();
+    assert_eq!(packed, json!([-8, 7, 1, 0, 3]));
+    let unpacked: Value = unpacker.unpack(&packed).unwrap();
+    assert_eq!(unpacked.to_string(), reordered.to_string());
+
+    let mut dump = Unpacker::new();
+    let text = disassemble(&mut dump, &frames).unwrap();
+    assert!(text.contains("TYPE_REORDERED #7 key positions [1,0]"));
+
+    // Without the option, each key order is memoised on its own.
+    let mut packer = Packer::new();
+    for _ in 0..2 {
+        packer.pack(&input, &PackOptions::new()).unwrap();
+    }
+    let packed = packer.pack(&input, &PackOptions::new()).unwrap();
+    assert_eq!(packed, json!([0, 8, 7, 2]));
+
+    // Objects memoised in either mode keep their key order in the other one.
+    let mut packer = Packer::new();
+    let mut mixed_unpacker = Unpacker::new();
+    let reordered = json!({"b": "x", "a": "y"});
+    let sorted = json!({"a": "y", "b": "x"});
+    for (value, options) in [
+        (&reordered, &options),
+        (&reordered, &options),
+        (&sorted, &PackOptions::new()),
+        (&sorted, &PackOptions::new()),
+        (&reordered, &options),
+    ] {
+        let packed = packer.pack(value, options).unwrap();
+        let unpacked: Value = mixed_unpacker.unpack(&packed).unwrap();
+        assert_eq!(unpacked.to_string(), value.to_string());
+    }
+
+    // Evicting a string spelled like a canonical key keeps the object memoised.
+    let mut packer = Packer::new();
+    let mut evicting_unpacker = Unpacker::new();
+    packer.set_max_dict_size(6);
+    evicting_unpacker.set_max_dict_size(6);
+    let object = json!({"a": "x", "b": "y"});
+    let mut frames = Vec::new();
+    for message in &[
+        json!([object.to_string()]),
+        object.clone(),
+        object.clone(),
+        json!(["z"]),
+        object.clone(),
+    ] {
+        let packed = packer.pack(message, &options).unwrap();
+        let unpacked: Value = evicting_unpacker.unpack(&packed).unwrap();
+        assert_eq!(unpacked.to_string(), message.to_string());
+        frames.push(packed);
+    }
+    assert_eq!(frames[4], json!([TYPE_VALUE, 8, 4]));
+
+    assert!(unpacker.unpack::<Value>(&json!([-8, 7, 0, 0, 4])).is_err());
+    assert!(unpacker.unpack::<Value>(&json!([-8, 3, 1, 0, 4])).is_err());
+}
@@ -0,0 +1,47 @@
+#![cfg(all(feature = "wasm", target_arch = "wasm32"))]
+extern crate jsonm;
+extern crate wasm_bindgen;
+extern crate wasm_bindgen_test;
+
+use jsonm::wasm::{WasmPacker, WasmUnpacker};
+use wasm_bindgen::JsValue;
+use wasm_bindgen_test::*;
+
+#[wasm_bindgen::prelude::wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = JSON)]
+    fn parse(json: &str) -> JsValue;
+    #[wasm_bindgen(js_namespace = JSON, js_name = stringify)]
+    fn stringify(value: &JsValue) -> String;
+}
+
+#[wasm_bindgen_test]
+fn it_packs_and_unpacks_js_values() {
+    let mut packer = WasmPacker::new();
+    let mut unpacker = WasmUnpacker::new();
+
+    let input = r#"{"foo":"bar","list":[1,"2",true,null]}"#;
+    let mut packed_sizes = Vec::new();
+    for _i in 0..2 {
+        let packed = packer.pack(parse(input)).unwrap();
+        packed_sizes.push(stringify(&packed).len());
+        let unpacked = unpacker.unpack(packed).unwrap();
+        assert_eq!(stringify(&unpacked), input);
+    }
+    assert!(packed_sizes[1] < packed_sizes[0]);
+}
+
+#[wasm_bindgen_test]
+fn it_packs_and_unpacks_js_strings() {
+    let mut packer = WasmPacker::new();
+    let mut unpacker = WasmUnpacker::new();
+
+    let packed = packer.pack_string("line 1\nline 2\n").unwrap();
+    assert_eq!(unpacker.unpack_string(packed).unwrap(), "line 1\nline 2\n");
+}
+
+#[wasm_bindgen_test]
+fn it_reports_unpacking_errors() {
+    let mut unpacker = WasmUnpacker::new();
+    assert!(unpacker.unpack(parse(r#"["foo", 5]"#)).is_err());
+}